@@ -1,39 +1,129 @@
-use bevy::prelude::*;
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            Extent3d,
+            TextureDimension,
+            TextureFormat,
+            TextureUsages,
+        }
+    },
+};
+use bevy_tasks::{ComputeTaskPool, ParallelSliceMut};
 
-use crate::color;
+use crate::color::{self, DensityScale};
+use crate::config::SimConfig;
+use crate::container::{Container, ContainerPose};
+use crate::kernel::Kernels;
 use crate::particle::ParticlePosition;
-use crate::consts::PIXEL_SIZE;
-use crate::consts_private::{BOX_HALF_SIZE, SCREEN_FACTOR};
+use crate::consts_private::SCREEN_FACTOR;
+use crate::spatial_hash::SpatialHash;
 
 #[derive(Component)]
 pub struct Background;
 
 // Convenience constants.
-const PIXEL_SIZE_F: f32 = PIXEL_SIZE as f32;
 const SCREEN_FACTOR_INV: f32 = 1.0 / SCREEN_FACTOR;
+const BYTES_PER_PIXEL: usize = 4;
+// How many image rows each task rasterizes.
+const ROWS_PER_TASK: usize = 8;
 
-fn idx_to_screen_space(x: u32, y: u32) -> Vec2 {
+fn idx_to_screen_space(x: u32, y: u32, config: &SimConfig) -> Vec2 {
+    let box_half_size = config.box_half_size();
+    let pixel_size = config.pixel_size as f32;
     Vec2 {
-        x: -BOX_HALF_SIZE.0 + (x as f32 + 0.5) * PIXEL_SIZE_F,
-        y: BOX_HALF_SIZE.1 - (y as f32 + 0.5) * PIXEL_SIZE_F,
+        x: -box_half_size.x + (x as f32 + 0.5) * pixel_size,
+        y: box_half_size.y - (y as f32 + 0.5) * pixel_size,
     } * SCREEN_FACTOR_INV
 }
 
+/// Spawns the sprite which shows the density field behind the particles.
+pub fn spawn(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    config: Res<SimConfig>,
+) {
+    // Set up background image texture.
+    let image_size = config.image_size();
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: image_size.x,
+            height: image_size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+    );
+    image.texture_descriptor.usage =
+        TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::RENDER_ATTACHMENT;
+
+    commands.spawn((
+        Sprite {
+            image: images.add(image),
+            custom_size: Some(image_size.as_vec2()),
+            ..Default::default()
+        },
+        Transform::default().with_scale(Vec3::new(
+            config.pixel_size as f32,
+            config.pixel_size as f32,
+            0.0,
+        )),
+        Background,
+    ));
+}
+
 pub fn update(
-    particles: Query<&ParticlePosition>,
-    sprite: Query<&mut Sprite, With<Background>>,
+    particles: Query<(Entity, &ParticlePosition)>,
+    sprite: Single<(&Sprite, &mut Transform), With<Background>>,
     mut images: ResMut<Assets<Image>>,
+    mut hash: Local<SpatialHash>,
+    container: Option<Res<Container>>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
 ) {
-    let image = images.get_mut(&sprite.single().image).unwrap();
-    let size = image.size();
-    for i in 0..size.x {
-        for j in 0..size.y {
-            let sample_point = idx_to_screen_space(i, j);
-            image.set_color_at(
-                i,
-                j,
-                color::for_density(sample_point, particles.iter()),
-            ).unwrap();
-        }
-    }
+    // Bucket the current positions so each pixel only samples nearby particles.
+    hash.rebuild(
+        particles.iter().map(|(entity, ParticlePosition(x))| (entity, *x)),
+        kernels.support_radius(),
+    );
+    let hash = &*hash;
+    let config = &*config;
+    let kernel = &*kernels.density;
+    let scale = DensityScale::new(kernel, config);
+
+    // Cover the container wherever it is, sampling the density where each pixel is drawn.
+    let pose = container.map_or(ContainerPose::at_rest(config), |container| container.pose);
+    let stretch = pose.half_size / config.physical_half_size();
+    let (sprite, mut transform) = sprite.into_inner();
+    transform.translation = (pose.centre * SCREEN_FACTOR).extend(transform.translation.z);
+    transform.rotation = Quat::from_rotation_z(pose.angle);
+    transform.scale = (config.pixel_size as f32 * stretch).extend(0.0);
+    let pose = &pose;
+
+    let image = images.get_mut(&sprite.image).unwrap();
+    let width = image.width();
+    let row_bytes = width as usize * BYTES_PER_PIXEL;
+    image.data.par_chunk_map_mut(
+        ComputeTaskPool::get(),
+        row_bytes * ROWS_PER_TASK,
+        |chunk_index, rows| {
+            let first_row = chunk_index * ROWS_PER_TASK;
+            for (row, pixels) in rows.chunks_exact_mut(row_bytes).enumerate() {
+                let j = (first_row + row) as u32;
+                for (i, pixel) in pixels.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+                    let sample_point =
+                        pose.to_world(idx_to_screen_space(i as u32, j, config) * stretch);
+                    let color = color::for_density(sample_point, hash, &scale, pose, kernel, config);
+                    // Match the Rgba8Unorm conversion done by `Image::set_color_at`.
+                    let rgba = LinearRgba::from(color).to_f32_array();
+                    for (byte, channel) in pixel.iter_mut().zip(rgba) {
+                        *byte = (channel * u8::MAX as f32) as u8;
+                    }
+                }
+            }
+        },
+    );
 }
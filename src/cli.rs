@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use flow::config::{ConfigError, SimConfig};
+
+#[derive(Parser)]
+#[command(about = "A basic particle-based fluid simulator")]
+pub struct Args {
+    /// TOML file overriding the default simulation config.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Seed for all randomness, overriding the config.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Run only the physics, without a window or renderer.
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Number of physics steps to run in headless mode.
+    #[arg(
+        long,
+        requires = "headless",
+        conflicts_with = "duration",
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub steps: Option<u32>,
+
+    /// Simulated time to run for in headless mode, in seconds.
+    #[arg(long, requires = "headless", value_parser = positive_seconds)]
+    pub duration: Option<f32>,
+
+    /// CSV file to write the final particle state to in headless mode.
+    #[arg(long, requires = "headless")]
+    pub output: Option<PathBuf>,
+
+    /// Snapshot file saved to with F5 and loaded from with F9.
+    #[arg(long, default_value = "snapshot.ron")]
+    pub snapshot: PathBuf,
+
+    /// Snapshot to start the simulation from.
+    #[arg(long)]
+    pub load: Option<PathBuf>,
+
+    /// Snapshot file to write the final state to in headless mode.
+    #[arg(long, requires = "headless")]
+    pub save: Option<PathBuf>,
+
+    /// Directory to record the particle state to, as CSV and VTK files.
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    /// How many seconds of simulation to keep for rewinding.
+    #[arg(long, default_value_t = 10.0)]
+    pub rewind: f32,
+
+    /// Play back a directory written by `--record` instead of simulating.
+    #[arg(long, conflicts_with_all = ["config", "headless", "load", "record"])]
+    pub replay: Option<PathBuf>,
+
+    /// Record every this many fixed steps.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub record_stride: u32,
+}
+
+// Run for ten seconds at the default timestep if no length was given.
+const DEFAULT_HEADLESS_STEPS: u32 = 600;
+
+fn positive_seconds(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(seconds),
+        Ok(_) => Err("must be a positive number of seconds".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl Args {
+    /// Loads the config file if one was given, otherwise uses the defaults.
+    pub fn load_config(&self) -> Result<SimConfig, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => SimConfig::load(path)?,
+            None => SimConfig::default(),
+        };
+        if self.seed.is_some() {
+            config.seed = self.seed;
+        }
+        Ok(config)
+    }
+
+    /// The number of fixed steps a headless run should take.
+    pub fn headless_steps(&self, config: &SimConfig) -> u32 {
+        match (self.steps, self.duration) {
+            (Some(steps), _) => steps,
+            (None, Some(duration)) => (duration / config.timestep).ceil() as u32,
+            (None, None) => DEFAULT_HEADLESS_STEPS,
+        }
+    }
+}
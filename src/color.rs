@@ -1,12 +1,13 @@
 use bevy::prelude::{Color, Srgba};
 use glam::f32::Vec2;
 
+use crate::config::SimConfig;
+use crate::container::ContainerPose;
 use crate::const_srgba_u8;
-use crate::consts::{DENSITY_KERNEL, EDGE_REPULSION, TARGET_DENSITY};
-use crate::consts_private::DENSITY_FACTOR;
+use crate::kernel::Kernel;
 use crate::maths::*;
-use crate::particle::ParticlePosition;
 use crate::physics;
+use crate::spatial_hash::SpatialHash;
 
 // Background colors.
 const COLOR_LOW_PRESSURE: Srgba = bevy::color::palettes::basic::BLUE;
@@ -17,47 +18,66 @@ const COLOR_HIGH_PRESSURE: Srgba = bevy::color::palettes::basic::RED;
 const PARTICLE_COLOR_SLOW: Srgba = const_srgba_u8!(32, 166, 214);
 const PARTICLE_COLOR_FAST: Srgba = const_srgba_u8!(214, 32, 32);
 
-// We assume that densities range in [0, N * kernel::MAX_VAL].
+// We assume that densities range in [0, N * Kernel::self_value].
 // That is with influence from 0 particles to influence from up to N particles.
 const N: f32 = 5.0;
 const MARGIN: f32 = 0.05;
-const DENSITY_UPPER_BOUND: f32 = N * DENSITY_FACTOR;
-const MARGIN_LOWER_BOUND: f32 = TARGET_DENSITY * (1.0 - MARGIN);
-const MARGIN_LOWER_BOUND_INV: f32 = 1.0 / MARGIN_LOWER_BOUND;
-const MARGIN_UPPER_BOUND: f32 =
-    TARGET_DENSITY + (DENSITY_UPPER_BOUND - TARGET_DENSITY) * MARGIN;
-const UPPER_DENSITY_RANGE: f32 = DENSITY_UPPER_BOUND - MARGIN_UPPER_BOUND;
-const UPPER_DENSITY_RANGE_INV: f32 = 1.0 / UPPER_DENSITY_RANGE;
 
-pub fn for_density<'a>(
+/// Density thresholds used to color the background.
+pub struct DensityScale {
+    margin_lower_bound: f32,
+    margin_lower_bound_inv: f32,
+    margin_upper_bound: f32,
+    upper_density_range_inv: f32,
+}
+
+impl DensityScale {
+    pub fn new(kernel: &dyn Kernel, config: &SimConfig) -> Self {
+        let density_upper_bound = N * kernel.self_value();
+        let margin_lower_bound = config.target_density * (1.0 - MARGIN);
+        let margin_upper_bound = config.target_density
+            + (density_upper_bound - config.target_density) * MARGIN;
+        Self {
+            margin_lower_bound,
+            margin_lower_bound_inv: 1.0 / margin_lower_bound,
+            margin_upper_bound,
+            upper_density_range_inv: 1.0 / (density_upper_bound - margin_upper_bound),
+        }
+    }
+}
+
+pub fn for_density(
     sample_point: Vec2,
-    particles: impl Iterator<Item = &'a ParticlePosition>,
+    particles: &SpatialHash,
+    scale: &DensityScale,
+    container: &ContainerPose,
+    kernel: &dyn Kernel,
+    config: &SimConfig,
 ) -> Color {
     // Compute the density at this point.
-    let mut density = if EDGE_REPULSION {
-        physics::compute_edge_density(&sample_point)
+    let mut density = if config.edge_repulsion {
+        physics::compute_edge_density(&sample_point, container, kernel, config)
     } else {
         0.0
     };
-    for ParticlePosition(pos_i) in particles {
-        let displacement_squared = (sample_point - pos_i).length_squared();
-        density += DENSITY_KERNEL.influence(displacement_squared);
+    for (displacement, _) in particles.periodic_neighbours(sample_point, container, config.periodic) {
+        density += kernel.value(displacement.length_squared());
     }
     // Color point relative to target density.
-    if density < MARGIN_LOWER_BOUND {
+    if density < scale.margin_lower_bound {
         lerp_color(
             &COLOR_LOW_PRESSURE,
             &COLOR_TARGET_PRESSURE,
-            density * MARGIN_LOWER_BOUND_INV,
+            density * scale.margin_lower_bound_inv,
         )
-    } else if density < MARGIN_UPPER_BOUND {
+    } else if density < scale.margin_upper_bound {
         Color::WHITE
     } else {
-        let density_error = density - MARGIN_UPPER_BOUND;
+        let density_error = density - scale.margin_upper_bound;
         lerp_color(
             &COLOR_TARGET_PRESSURE,
             &COLOR_HIGH_PRESSURE,
-            1.0_f32.min(density_error * UPPER_DENSITY_RANGE_INV),
+            1.0_f32.min(density_error * scale.upper_density_range_inv),
         )
     }
 }
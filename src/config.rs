@@ -0,0 +1,392 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::consts::*;
+use crate::consts_private::SCREEN_FACTOR;
+use crate::container::ContainerMotion;
+use crate::emitter::{DrainConfig, EmitterConfig};
+use crate::obstacle::Obstacle;
+use crate::kernel::{KernelKind, Kernels};
+use crate::physics::EquationOfState;
+use crate::simulation::Solver;
+
+/// Every tunable of the simulation.
+/// Fields missing from a config file take the defaults in `consts.rs`.
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    // Window dimensions in screen space.
+    pub window_size: (u32, u32),
+    // Pixel scale factor of the background image.
+    pub pixel_size: u32,
+    // Simulation box dimensions in screen space.
+    pub box_size: (u32, u32),
+    pub box_line_width: f32,
+    pub num_particles: usize,
+    pub max_particles: usize,
+    pub particle_screen_radius: f32,
+    pub multithreaded: bool,
+    // Seeds all randomness, picked at random if not set.
+    pub seed: Option<u64>,
+    pub timestep: f32,
+    pub substeps: u32,
+    pub adaptive_timestep: bool,
+    pub cfl_number: f32,
+    pub max_substeps: u32,
+    pub collision_damping: f32,
+    pub edge_repulsion: bool,
+    pub periodic: (bool, bool),
+    pub startup_damping: bool,
+    pub startup_damping_interval: f32,
+    pub gravity_force: f32,
+    pub target_density: f32,
+    pub pressure_multiplier: f32,
+    pub equation_of_state: EquationOfState,
+    pub speed_of_sound: f32,
+    pub tait_exponent: f32,
+    pub near_density: bool,
+    pub near_pressure_multiplier: f32,
+    pub interaction_radius: f32,
+    pub interaction_strength: f32,
+    // How far the built in kernels reach.
+    pub smoothing_radius: f32,
+    pub density_kernel: KernelKind,
+    pub viscosity: f32,
+    pub viscosity_kernel: KernelKind,
+    pub surface_tension: f32,
+    pub solver: Solver,
+    pub pbf_iterations: u32,
+    pub pbf_relaxation: f32,
+    pub pbf_tensile_strength: f32,
+    pub pbf_tensile_exponent: u32,
+    pub pbf_tensile_distance: f32,
+    pub pcisph_max_density_error: f32,
+    pub pcisph_max_iterations: u32,
+    // Sources and sinks of particles, in physical space.
+    pub emitters: Vec<EmitterConfig>,
+    pub drains: Vec<DrainConfig>,
+    // Static shapes inside the box, in physical space.
+    pub obstacles: Vec<Obstacle>,
+    // Scripted movement of the container, which is otherwise moved by the keyboard.
+    pub container_motion: Option<ContainerMotion>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            window_size: WINDOW_SIZE,
+            pixel_size: PIXEL_SIZE,
+            box_size: BOX_SIZE,
+            box_line_width: BOX_LINE_WIDTH,
+            num_particles: NUM_PARTICLES,
+            max_particles: MAX_PARTICLES,
+            particle_screen_radius: PARTICLE_SCREEN_RADIUS,
+            multithreaded: MULTITHREADED,
+            seed: None,
+            timestep: TIMESTEP,
+            substeps: SUBSTEPS,
+            adaptive_timestep: ADAPTIVE_TIMESTEP,
+            cfl_number: CFL_NUMBER,
+            max_substeps: MAX_SUBSTEPS,
+            collision_damping: COLLISION_DAMPING,
+            edge_repulsion: EDGE_REPULSION,
+            periodic: PERIODIC,
+            startup_damping: STARTUP_DAMPING,
+            startup_damping_interval: STARTUP_DAMPING_INTERVAL,
+            gravity_force: GRAVITY_FORCE,
+            target_density: TARGET_DENSITY,
+            pressure_multiplier: PRESSURE_MULTIPLIER,
+            equation_of_state: EQUATION_OF_STATE,
+            speed_of_sound: SPEED_OF_SOUND,
+            tait_exponent: TAIT_EXPONENT,
+            near_density: NEAR_DENSITY,
+            near_pressure_multiplier: NEAR_PRESSURE_MULTIPLIER,
+            interaction_radius: INTERACTION_RADIUS,
+            interaction_strength: INTERACTION_STRENGTH,
+            smoothing_radius: SMOOTHING_RADIUS,
+            density_kernel: DENSITY_KERNEL,
+            viscosity: VISCOSITY,
+            viscosity_kernel: VISCOSITY_KERNEL,
+            surface_tension: SURFACE_TENSION,
+            solver: SOLVER,
+            pbf_iterations: PBF_ITERATIONS,
+            pbf_relaxation: PBF_RELAXATION,
+            pbf_tensile_strength: PBF_TENSILE_STRENGTH,
+            pbf_tensile_exponent: PBF_TENSILE_EXPONENT,
+            pbf_tensile_distance: PBF_TENSILE_DISTANCE,
+            pcisph_max_density_error: PCISPH_MAX_DENSITY_ERROR,
+            pcisph_max_iterations: PCISPH_MAX_ITERATIONS,
+            emitters: Vec::new(),
+            drains: Vec::new(),
+            obstacles: Vec::new(),
+            container_motion: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid { field: &'static str, reason: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) =>
+                write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) =>
+                write!(f, "could not parse {}: {}", path.display(), err),
+            ConfigError::Invalid { field, reason } =>
+                write!(f, "invalid value for `{}`: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl SimConfig {
+    /// Loads a config from a TOML file and checks that its values make sense.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        let config: SimConfig = toml::from_str(&text)
+            .map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason| Err(ConfigError::Invalid { field, reason });
+        if self.window_size.0 == 0 || self.window_size.1 == 0 {
+            return invalid("window_size", "must be non-zero");
+        }
+        if self.pixel_size == 0 {
+            return invalid("pixel_size", "must be non-zero");
+        }
+        if self.box_size.0 < self.pixel_size || self.box_size.1 < self.pixel_size {
+            return invalid("box_size", "must be at least one pixel in each dimension");
+        }
+        if self.max_particles < self.num_particles {
+            return invalid("max_particles", "must be at least `num_particles`");
+        }
+        if self.particle_screen_radius <= 0.0 {
+            return invalid("particle_screen_radius", "must be positive");
+        }
+        if self.timestep <= 0.0 {
+            return invalid("timestep", "must be positive");
+        }
+        if self.substeps == 0 {
+            return invalid("substeps", "must be at least 1");
+        }
+        if self.cfl_number <= 0.0 {
+            return invalid("cfl_number", "must be positive");
+        }
+        if self.max_substeps < self.substeps {
+            return invalid("max_substeps", "must be at least `substeps`");
+        }
+        // Smaller periodic boxes would see particles twice, through both sides.
+        let size = 2.0 * self.physical_half_size();
+        let reach = 2.0 * Kernels::new(self).support_radius();
+        if (self.periodic.0 && size.x <= reach) || (self.periodic.1 && size.y <= reach) {
+            return invalid("periodic", "periodic sides must be over two kernel support radii apart");
+        }
+        if !(0.0..=1.0).contains(&self.collision_damping) {
+            return invalid("collision_damping", "must be in [0, 1]");
+        }
+        if self.startup_damping_interval <= 0.0 {
+            return invalid("startup_damping_interval", "must be positive");
+        }
+        if self.target_density <= 0.0 {
+            return invalid("target_density", "must be positive");
+        }
+        if self.pressure_multiplier < 0.0 {
+            return invalid("pressure_multiplier", "must not be negative");
+        }
+        if self.speed_of_sound <= 0.0 {
+            return invalid("speed_of_sound", "must be positive");
+        }
+        if self.tait_exponent <= 0.0 {
+            return invalid("tait_exponent", "must be positive");
+        }
+        if self.near_pressure_multiplier < 0.0 {
+            return invalid("near_pressure_multiplier", "must not be negative");
+        }
+        if self.interaction_radius <= 0.0 {
+            return invalid("interaction_radius", "must be positive");
+        }
+        if self.interaction_strength < 0.0 {
+            return invalid("interaction_strength", "must not be negative");
+        }
+        if self.smoothing_radius <= 0.0 {
+            return invalid("smoothing_radius", "must be positive");
+        }
+        if self.density_kernel == KernelKind::Viscosity {
+            return invalid("density_kernel", "`Viscosity` is infinite at the centre, so can't measure density");
+        }
+        if self.viscosity_kernel != KernelKind::Viscosity {
+            return invalid("viscosity_kernel", "only `Viscosity` has a Laplacian which is positive everywhere");
+        }
+        if self.viscosity < 0.0 {
+            return invalid("viscosity", "must not be negative");
+        }
+        if self.surface_tension < 0.0 {
+            return invalid("surface_tension", "must not be negative");
+        }
+        if self.pbf_iterations == 0 {
+            return invalid("pbf_iterations", "must be at least 1");
+        }
+        if self.pbf_relaxation <= 0.0 {
+            return invalid("pbf_relaxation", "must be positive");
+        }
+        if self.pbf_tensile_strength < 0.0 {
+            return invalid("pbf_tensile_strength", "must not be negative");
+        }
+        if self.pbf_tensile_distance <= 0.0 || self.pbf_tensile_distance >= 1.0 {
+            return invalid("pbf_tensile_distance", "must be between 0 and 1");
+        }
+        if self.pcisph_max_density_error <= 0.0 {
+            return invalid("pcisph_max_density_error", "must be positive");
+        }
+        if self.pcisph_max_iterations == 0 {
+            return invalid("pcisph_max_iterations", "must be at least 1");
+        }
+        for emitter in &self.emitters {
+            if emitter.direction == (0.0, 0.0) {
+                return invalid("emitters", "`direction` must be non-zero");
+            }
+            if emitter.rate < 0.0 || emitter.speed < 0.0 || emitter.spread < 0.0 {
+                return invalid("emitters", "`rate`, `speed` and `spread` must not be negative");
+            }
+        }
+        for drain in &self.drains {
+            if drain.size.0 <= 0.0 || drain.size.1 <= 0.0 {
+                return invalid("drains", "`size` must be positive");
+            }
+        }
+        for obstacle in &self.obstacles {
+            let valid = match obstacle {
+                Obstacle::Circle { radius, .. } | Obstacle::Capsule { radius, .. } => *radius > 0.0,
+                Obstacle::Rect { size, .. } => size.0 > 0.0 && size.1 > 0.0,
+                Obstacle::Polygon { points } => points.len() >= 3,
+            };
+            if !valid {
+                return invalid(
+                    "obstacles", "sizes must be positive and polygons need at least 3 points",
+                );
+            }
+        }
+        if let Some(motion) = &self.container_motion {
+            if motion.keyframes.is_empty() {
+                return invalid("container_motion", "needs at least one keyframe");
+            }
+            if motion.keyframes.windows(2).any(|pair| pair[1].time < pair[0].time) {
+                return invalid("container_motion", "keyframe times must not decrease");
+            }
+            if motion.keyframes.iter().any(|keyframe| keyframe.scale.0 <= 0.0 || keyframe.scale.1 <= 0.0) {
+                return invalid("container_motion", "keyframe scales must be positive");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn timestep_duration(&self) -> Duration {
+        Duration::from_secs_f32(self.timestep)
+    }
+
+    pub fn window_size_f(&self) -> Vec2 {
+        Vec2::new(self.window_size.0 as f32, self.window_size.1 as f32)
+    }
+
+    pub fn box_size_f(&self) -> Vec2 {
+        Vec2::new(self.box_size.0 as f32, self.box_size.1 as f32)
+    }
+
+    pub fn box_half_size(&self) -> Vec2 {
+        0.5 * self.box_size_f()
+    }
+
+    pub fn box_line_centre(&self) -> Vec2 {
+        self.box_half_size() + 0.5 * self.box_line_width
+    }
+
+    pub fn image_size(&self) -> UVec2 {
+        UVec2::new(self.box_size.0, self.box_size.1) / self.pixel_size
+    }
+
+    // The size of the box in physical space.
+    pub fn physical_half_size(&self) -> Vec2 {
+        self.box_half_size() / SCREEN_FACTOR
+    }
+
+    pub fn particle_radius(&self) -> f32 {
+        self.particle_screen_radius / SCREEN_FACTOR
+    }
+
+    // The allowed range for the centre of a particle.
+    pub fn particle_centre_bound(&self) -> Vec2 {
+        self.physical_half_size() - self.particle_radius()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The field `validate` rejects `config` for.
+    fn invalid_field(config: SimConfig) -> &'static str {
+        match config.validate() {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        SimConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn partial_files_keep_defaults() {
+        let config: SimConfig = toml::from_str("num_particles = 100\ntimestep = 0.01").unwrap();
+        assert_eq!(config, SimConfig { num_particles: 100, timestep: 0.01, ..Default::default() });
+        assert!(toml::from_str::<SimConfig>("no_such_field = 1").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let cases = [
+            ("pixel_size", SimConfig { pixel_size: 0, ..Default::default() }),
+            ("box_size", SimConfig { box_size: (2, 700), ..Default::default() }),
+            ("max_particles", SimConfig { max_particles: 10, num_particles: 20, ..Default::default() }),
+            ("timestep", SimConfig { timestep: 0.0, ..Default::default() }),
+            ("substeps", SimConfig { substeps: 0, ..Default::default() }),
+            ("max_substeps", SimConfig { substeps: 4, max_substeps: 2, ..Default::default() }),
+            ("periodic", SimConfig { box_size: (200, 700), periodic: (true, false), ..Default::default() }),
+            ("collision_damping", SimConfig { collision_damping: 1.5, ..Default::default() }),
+            ("target_density", SimConfig { target_density: -1.0, ..Default::default() }),
+            ("smoothing_radius", SimConfig { smoothing_radius: 0.0, ..Default::default() }),
+            ("density_kernel", SimConfig { density_kernel: KernelKind::Viscosity, ..Default::default() }),
+            ("viscosity_kernel", SimConfig { viscosity_kernel: KernelKind::Spiky2, ..Default::default() }),
+            ("viscosity", SimConfig { viscosity: -0.1, ..Default::default() }),
+            ("pbf_tensile_distance", SimConfig { pbf_tensile_distance: 1.0, ..Default::default() }),
+            ("pcisph_max_iterations", SimConfig { pcisph_max_iterations: 0, ..Default::default() }),
+            ("obstacles", SimConfig {
+                obstacles: vec![Obstacle::Circle { centre: (0.0, 0.0), radius: 0.0 }],
+                ..Default::default()
+            }),
+            ("container_motion", SimConfig {
+                container_motion: Some(ContainerMotion { looped: false, keyframes: Vec::new() }),
+                ..Default::default()
+            }),
+        ];
+        for (field, config) in cases {
+            assert_eq!(invalid_field(config), field);
+        }
+    }
+}
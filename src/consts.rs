@@ -1,6 +1,9 @@
-// Constants which the user might want to play with.
+// Default values for `SimConfig`, which the user might want to play with
+// without writing a config file.
 
-use crate::kernel::Kernel;
+use crate::kernel::KernelKind;
+use crate::physics::EquationOfState;
+use crate::simulation::Solver;
 
 // Window dimensions in screen space.
 pub const WINDOW_SIZE: (u32, u32) = (1600, 900);
@@ -16,15 +19,34 @@ pub const BOX_LINE_WIDTH: f32 = 2.0;
 
 // The number of particles to spawn.
 pub const NUM_PARTICLES: usize = 400;
+// The most particles there may be once emitters are adding more.
+pub const MAX_PARTICLES: usize = 2000;
 // The radius of each particle on the screen.
 pub const PARTICLE_SCREEN_RADIUS: f32 = 5.0;
 
 
+// Should the per-particle physics passes be split across threads.
+// Disable to compare against the single-threaded path.
+pub const MULTITHREADED: bool = true;
+
+
 // Physical constants.
+// How long each fixed physics step is in seconds.
+pub const TIMESTEP: f32 = 1.0 / 60.0;
+// How many substeps to split each fixed step into.
+pub const SUBSTEPS: u32 = 1;
+// Should substeps instead be as long as stability allows.
+pub const ADAPTIVE_TIMESTEP: bool = false;
+// Fraction of a smoothing radius the fastest particle may move per adaptive substep.
+pub const CFL_NUMBER: f32 = 0.4;
+// The most adaptive substeps to take per fixed step, even if that's unstable.
+pub const MAX_SUBSTEPS: u32 = 32;
 // How should the velocity be scaled on collision.
 pub const COLLISION_DAMPING: f32 = 0.5;
 // Should particles be repelled from the edge of the box.
 pub const EDGE_REPULSION: bool = false;
+// Should particles leaving through the (x, y) sides of the box re-enter on the opposite side.
+pub const PERIODIC: (bool, bool) = (false, false);
 // Should we apply startup damping.
 pub const STARTUP_DAMPING: bool = false;
 // How long should we take to ramp up to full pressure in seconds.
@@ -37,9 +59,41 @@ pub const SMOOTHING_RADIUS: f32 = 1.2;
 pub const TARGET_DENSITY: f32 = 2.75;
 // How strong should the pressure force be.
 pub const PRESSURE_MULTIPLIER: f32 = 65.0;
+// How pressure follows from density.
+pub const EQUATION_OF_STATE: EquationOfState = EquationOfState::Linear;
+// How fast sound travels through the fluid, which sets how stiff the Tait equation of state is.
+pub const SPEED_OF_SOUND: f32 = 4.0;
+// The exponent of the Tait equation of state.
+pub const TAIT_EXPONENT: f32 = 7.0;
+// Should particles also be pushed apart by near-density, which stops them clumping.
+pub const NEAR_DENSITY: bool = false;
+// How strong should the near-density pressure force be.
+pub const NEAR_PRESSURE_MULTIPLIER: f32 = 10.0;
 // Which kernel to use to compute particle influence.
-pub const DENSITY_KERNEL: Kernel = Kernel::Spiky2;
+pub const DENSITY_KERNEL: KernelKind = KernelKind::Spiky2;
+// How far from the cursor the mouse force reaches.
+pub const INTERACTION_RADIUS: f32 = 2.0;
+// How strongly the mouse pulls or pushes particles at the cursor.
+pub const INTERACTION_STRENGTH: f32 = 40.0;
 // How viscous is the fluid.
 pub const VISCOSITY: f32 = 0.1;
 // How should viscous particles influence each other.
-pub const VISCOSITY_KERNEL: Kernel = Kernel::Smooth6;
+pub const VISCOSITY_KERNEL: KernelKind = KernelKind::Viscosity;
+// How each substep moves the particles.
+pub const SOLVER: Solver = Solver::Sph;
+// How many times per substep the PBF solver corrects positions towards the target density.
+pub const PBF_ITERATIONS: u32 = 4;
+// Softens the PBF density constraints, which keeps them stable where particles are sparse.
+pub const PBF_RELAXATION: f32 = 1.0;
+// The strength, exponent and reference distance, in smoothing radii, of the PBF
+// artificial pressure which stops particles clumping together.
+pub const PBF_TENSILE_STRENGTH: f32 = 0.1;
+pub const PBF_TENSILE_EXPONENT: u32 = 4;
+pub const PBF_TENSILE_DISTANCE: f32 = 0.2;
+// The PCISPH solver stops correcting pressures once no particle is predicted to be
+// denser than the target by more than this fraction of it.
+pub const PCISPH_MAX_DENSITY_ERROR: f32 = 0.01;
+// The most pressure corrections the PCISPH solver makes per substep.
+pub const PCISPH_MAX_ITERATIONS: u32 = 50;
+// How strongly particles at the surface pull together, zero to disable.
+pub const SURFACE_TENSION: f32 = 0.0;
@@ -0,0 +1,291 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimConfig;
+use crate::consts_private::SCREEN_FACTOR;
+use crate::kernel::Kernels;
+use crate::simulation::SimClock;
+
+// How fast the keyboard moves, tilts and resizes the container.
+const KEY_SPEED: f32 = 3.0;
+const KEY_ANGULAR_SPEED: f32 = 1.0;
+const KEY_GROWTH_RATE: f32 = 0.5;
+// The smallest the container may be shrunk to, as a fraction of `box_size`.
+const MIN_SCALE: f32 = 0.2;
+
+/// A point the scripted container motion passes through.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keyframe {
+    // Simulated time, in seconds.
+    pub time: f32,
+    // Offset of the container centre from the origin, in physical space.
+    #[serde(default)]
+    pub centre: (f32, f32),
+    // Anticlockwise rotation, in degrees.
+    #[serde(default)]
+    pub angle: f32,
+    // Size relative to `box_size`.
+    #[serde(default = "unit_scale")]
+    pub scale: (f32, f32),
+}
+
+fn unit_scale() -> (f32, f32) {
+    (1.0, 1.0)
+}
+
+/// Moves the container along straight lines between keyframes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContainerMotion {
+    pub keyframes: Vec<Keyframe>,
+    // Start again from the first keyframe after the last.
+    #[serde(default)]
+    pub looped: bool,
+}
+
+impl ContainerMotion {
+    pub fn pose_at(&self, time: f32, config: &SimConfig, kernels: &Kernels) -> ContainerPose {
+        let first = &self.keyframes[0];
+        let last = &self.keyframes[self.keyframes.len() - 1];
+        let time = if self.looped && last.time > first.time {
+            first.time + (time - first.time).rem_euclid(last.time - first.time)
+        } else {
+            time
+        };
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time <= time);
+        let (a, b) = match next {
+            0 => (first, first),
+            n if n == self.keyframes.len() => (last, last),
+            n => (&self.keyframes[n - 1], &self.keyframes[n]),
+        };
+        let t = if b.time > a.time { (time - a.time) / (b.time - a.time) } else { 0.0 };
+        let half_size = config.physical_half_size();
+        ContainerPose {
+            centre: Vec2::from(a.centre).lerp(b.centre.into(), t),
+            angle: (a.angle + (b.angle - a.angle) * t).to_radians(),
+            half_size: (half_size * Vec2::from(a.scale).lerp(b.scale.into(), t))
+                .max(min_half_size(config, kernels)),
+        }
+    }
+}
+
+/// The smallest the container may be shrunk to. Periodic sides are kept
+/// two support radii apart, so no particle sees a neighbour through both.
+pub fn min_half_size(config: &SimConfig, kernels: &Kernels) -> Vec2 {
+    let radius = kernels.support_radius();
+    let periodic = Vec2::new(
+        if config.periodic.0 { radius } else { 0.0 },
+        if config.periodic.1 { radius } else { 0.0 },
+    );
+    (config.physical_half_size() * MIN_SCALE).max(periodic)
+}
+
+/// Where the container is, in physical space.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ContainerPose {
+    pub centre: Vec2,
+    // Anticlockwise rotation, in radians.
+    pub angle: f32,
+    pub half_size: Vec2,
+}
+
+impl ContainerPose {
+    pub fn at_rest(config: &SimConfig) -> Self {
+        Self {
+            centre: Vec2::ZERO,
+            angle: 0.0,
+            half_size: config.physical_half_size(),
+        }
+    }
+
+    pub fn to_local(&self, point: Vec2) -> Vec2 {
+        Vec2::from_angle(-self.angle).rotate(point - self.centre)
+    }
+
+    pub fn to_world(&self, point: Vec2) -> Vec2 {
+        self.centre + Vec2::from_angle(self.angle).rotate(point)
+    }
+
+    /// Rotates a direction from container space to world space.
+    pub fn rotate(&self, direction: Vec2) -> Vec2 {
+        Vec2::from_angle(self.angle).rotate(direction)
+    }
+
+    /// Offsets from `point` to its images across the `periodic` sides it is
+    /// within `reach` of, starting with zero for the point itself.
+    pub fn image_offsets(
+        &self,
+        point: Vec2,
+        periodic: (bool, bool),
+        reach: f32,
+    ) -> impl Iterator<Item = Vec2> {
+        let local = self.to_local(point);
+        let shift = |periodic: bool, x: f32, half_size: f32| {
+            if !periodic {
+                0.0
+            } else if x > half_size - reach {
+                -2.0 * half_size
+            } else if x < reach - half_size {
+                2.0 * half_size
+            } else {
+                0.0
+            }
+        };
+        let shift = Vec2::new(
+            shift(periodic.0, local.x, self.half_size.x),
+            shift(periodic.1, local.y, self.half_size.y),
+        );
+        let wanted = [true, shift.x != 0.0, shift.y != 0.0, shift.x != 0.0 && shift.y != 0.0];
+        let pose = *self;
+        [Vec2::ZERO, shift.with_y(0.0), shift.with_x(0.0), shift]
+            .into_iter()
+            .zip(wanted)
+            .filter(|(_, wanted)| *wanted)
+            .map(move |(offset, _)| pose.rotate(offset))
+    }
+}
+
+/// The container at the start and end of the current substep,
+/// so walls can push particles with their own velocity.
+#[derive(Resource, Clone, Copy)]
+pub struct Container {
+    pub pose: ContainerPose,
+    pub prev_pose: ContainerPose,
+}
+
+impl Container {
+    pub fn new(pose: ContainerPose) -> Self {
+        Self { pose, prev_pose: pose }
+    }
+
+    /// Where the container starts, following its script if it has one.
+    pub fn initial(config: &SimConfig, kernels: &Kernels) -> Self {
+        Self::new(match &config.container_motion {
+            Some(motion) => motion.pose_at(0.0, config, kernels),
+            None => ContainerPose::at_rest(config),
+        })
+    }
+
+    /// Container space coordinates at the end of the substep of a point
+    /// which moved with the container from `point` at the start of it.
+    pub fn prev_to_local(&self, point: Vec2) -> Vec2 {
+        let scale = self.pose.half_size / self.prev_pose.half_size;
+        self.prev_pose.to_local(point) * scale
+    }
+
+    /// Inverse of `prev_to_local`.
+    pub fn local_to_prev(&self, point: Vec2) -> Vec2 {
+        let scale = self.prev_pose.half_size / self.pose.half_size;
+        self.prev_pose.to_world(point * scale)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.pose != self.prev_pose
+    }
+
+    /// The velocity of the container at the container space `point`.
+    pub fn velocity_at(&self, point: Vec2, dt: f32) -> Vec2 {
+        (self.pose.to_world(point) - self.local_to_prev(point)) / dt
+    }
+}
+
+/// How the keyboard is moving the container, per second.
+#[derive(Resource, Default)]
+pub struct ContainerInput {
+    pub velocity: Vec2,
+    pub angular_velocity: f32,
+    pub growth: f32,
+}
+
+/// Moves the container to where it should be at the end of the substep.
+pub fn update(
+    mut container: ResMut<Container>,
+    input: Res<ContainerInput>,
+    clock: Res<SimClock>,
+    config: Res<SimConfig>,
+    kernels: Res<Kernels>,
+) {
+    container.prev_pose = container.pose;
+    match &config.container_motion {
+        Some(motion) => {
+            container.pose = motion.pose_at(clock.elapsed + clock.dt, &config, &kernels);
+        }
+        None => {
+            let dt = clock.dt;
+            let min_size = min_half_size(&config, &kernels);
+            let pose = &mut container.pose;
+            pose.centre += input.velocity * dt;
+            pose.angle += input.angular_velocity * dt;
+            pose.half_size = (pose.half_size * (1.0 + input.growth * dt))
+                .max(min_size);
+        }
+    }
+}
+
+/// WASD shakes the container, Q and E tilt it, and Z and X shrink and grow it.
+pub fn controls(keys: Option<Res<ButtonInput<KeyCode>>>, mut input: ResMut<ContainerInput>) {
+    let Some(keys) = keys else {
+        return;
+    };
+    let axis = |negative, positive| {
+        keys.pressed(positive) as i32 as f32 - keys.pressed(negative) as i32 as f32
+    };
+    input.velocity = KEY_SPEED * Vec2::new(
+        axis(KeyCode::KeyA, KeyCode::KeyD),
+        axis(KeyCode::KeyS, KeyCode::KeyW),
+    );
+    input.angular_velocity = KEY_ANGULAR_SPEED * axis(KeyCode::KeyE, KeyCode::KeyQ);
+    input.growth = KEY_GROWTH_RATE * axis(KeyCode::KeyZ, KeyCode::KeyX);
+}
+
+/// One side of the container's outline. `normal` points out of the container.
+#[derive(Component)]
+pub struct BoxSide {
+    pub normal: Vec2,
+}
+
+/// Keeps the outline drawn around the container.
+pub fn update_outline(
+    mut sides: Query<(&mut Transform, &BoxSide)>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    let pose = container.pose;
+    let half_size = pose.half_size * SCREEN_FACTOR;
+    let rest_size = config.box_half_size();
+    for (mut transform, BoxSide { normal }) in &mut sides {
+        let offset = *normal * (half_size + 0.5 * config.box_line_width);
+        transform.translation = (pose.to_world(offset / SCREEN_FACTOR) * SCREEN_FACTOR)
+            .extend(transform.translation.z);
+        transform.rotation = Quat::from_rotation_z(pose.angle);
+        // Stretch each side along its length to match the container's size.
+        let stretch = (half_size + config.box_line_width) / (rest_size + config.box_line_width);
+        transform.scale = if normal.x != 0.0 {
+            Vec3::new(1.0, stretch.y, 1.0)
+        } else {
+            Vec3::new(stretch.x, 1.0, 1.0)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periodic_sides_stay_two_support_radii_apart() {
+        let config = SimConfig {
+            periodic: (true, false),
+            container_motion: Some(ContainerMotion {
+                keyframes: vec![Keyframe { time: 0.0, centre: (0.0, 0.0), angle: 0.0, scale: (0.01, 0.01) }],
+                looped: false,
+            }),
+            ..Default::default()
+        };
+        let kernels = Kernels::new(&config);
+        let half_size = Container::initial(&config, &kernels).pose.half_size;
+        assert_eq!(half_size.x, kernels.support_radius());
+        assert_eq!(half_size.y, config.physical_half_size().y * MIN_SCALE);
+    }
+}
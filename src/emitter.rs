@@ -0,0 +1,154 @@
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimConfig;
+use crate::consts_private::SCREEN_FACTOR;
+use crate::container::Container;
+use crate::particle::{self, ParticlePosition};
+use crate::random::SimRng;
+
+const EMITTER_COLOR: Color = Color::srgb(0.3, 1.0, 0.5);
+const DRAIN_COLOR: Color = Color::srgb(1.0, 0.5, 0.2);
+// How long the arrow showing an emitter's direction is, in screen space.
+const EMITTER_ARROW_LENGTH: f32 = 40.0;
+
+/// An emitter as written in a config file, in physical space.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmitterConfig {
+    pub position: (f32, f32),
+    pub direction: (f32, f32),
+    // Particles emitted per second.
+    pub rate: f32,
+    pub speed: f32,
+    // Largest angle between a particle's velocity and `direction`, in degrees.
+    #[serde(default)]
+    pub spread: f32,
+}
+
+/// A drain as written in a config file, in physical space.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DrainConfig {
+    pub centre: (f32, f32),
+    pub size: (f32, f32),
+}
+
+/// Spawns particles during the run.
+#[derive(Component)]
+pub struct Emitter {
+    pub position: Vec2,
+    pub direction: Vec2,
+    pub rate: f32,
+    pub speed: f32,
+    // In radians.
+    pub spread: f32,
+    // Particles owed from previous steps, less than one.
+    pub(crate) pending: f32,
+}
+
+/// Despawns particles which enter its rectangle.
+#[derive(Component)]
+pub struct Drain {
+    pub centre: Vec2,
+    pub half_size: Vec2,
+}
+
+impl Drain {
+    pub fn contains(&self, point: Vec2) -> bool {
+        let offset = (point - self.centre).abs();
+        offset.x <= self.half_size.x && offset.y <= self.half_size.y
+    }
+}
+
+pub fn spawn(mut commands: Commands, config: Res<SimConfig>) {
+    for emitter in &config.emitters {
+        commands.spawn(Emitter {
+            position: emitter.position.into(),
+            direction: Vec2::from(emitter.direction).normalize(),
+            rate: emitter.rate,
+            speed: emitter.speed,
+            spread: emitter.spread.to_radians(),
+            pending: 0.0,
+        });
+    }
+    for drain in &config.drains {
+        commands.spawn(Drain {
+            centre: drain.centre.into(),
+            half_size: 0.5 * Vec2::from(drain.size),
+        });
+    }
+}
+
+/// Spawns each emitter's particles for the latest fixed step,
+/// while there are fewer than `max_particles`.
+pub fn emit(
+    mut commands: Commands,
+    mut emitters: Query<&mut Emitter>,
+    particles: Query<(), With<ParticlePosition>>,
+    mut rng: ResMut<SimRng>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    let mut count = particles.iter().len();
+    let radius = config.particle_radius();
+    let pose = &container.pose;
+    let bound = pose.half_size - radius;
+    for mut emitter in &mut emitters {
+        emitter.pending += emitter.rate * config.timestep;
+        while emitter.pending >= 1.0 {
+            emitter.pending -= 1.0;
+            // Particles over the cap are dropped rather than saved up.
+            if count >= config.max_particles {
+                continue;
+            }
+            let rng = rng.rng();
+            // Spread particles across the emitter so they don't start on top of each other.
+            let across = emitter.direction.perp() * rng.gen_range(-radius..=radius);
+            // Keep new particles inside the container, wherever it has moved.
+            let position = pose.to_world(
+                pose.to_local(emitter.position + across).clamp(-bound, bound),
+            );
+            let angle = if emitter.spread > 0.0 {
+                rng.gen_range(-emitter.spread..=emitter.spread)
+            } else {
+                0.0
+            };
+            let velocity = Vec2::from_angle(angle).rotate(emitter.direction) * emitter.speed;
+            commands.spawn(particle::new_particle(position, velocity));
+            count += 1;
+        }
+    }
+}
+
+pub fn drain(
+    mut commands: Commands,
+    drains: Query<&Drain>,
+    particles: Query<(Entity, &ParticlePosition)>,
+) {
+    for (entity, ParticlePosition(x)) in &particles {
+        if drains.iter().any(|drain| drain.contains(*x)) {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Outlines drains and marks emitters with an arrow along their direction.
+pub fn draw(
+    mut gizmos: Gizmos,
+    emitters: Query<&Emitter>,
+    drains: Query<&Drain>,
+) {
+    for emitter in &emitters {
+        let start = emitter.position * SCREEN_FACTOR;
+        gizmos.arrow_2d(start, start + emitter.direction * EMITTER_ARROW_LENGTH, EMITTER_COLOR);
+    }
+    for drain in &drains {
+        gizmos.rect_2d(
+            Isometry2d::from_translation(drain.centre * SCREEN_FACTOR),
+            2.0 * drain.half_size * SCREEN_FACTOR,
+            DRAIN_COLOR,
+        );
+    }
+}
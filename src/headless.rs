@@ -0,0 +1,126 @@
+use std::path::PathBuf;
+
+use bevy::{log::LogPlugin, prelude::*, time::TimeUpdateStrategy};
+
+use crate::cli::Args;
+
+use flow::config::SimConfig;
+use flow::particle::{ParticleDensity, ParticlePosition, ParticleVelocity};
+use flow::random::SimRng;
+use flow::recorder::{self, ParticleRecord, RecorderPlugin};
+use flow::simulation::{AverageEK, SimClock, SimulationPlugin, SimulationSet};
+use flow::snapshot::{LoadSnapshot, SaveSnapshot};
+
+#[derive(Resource)]
+struct HeadlessRun {
+    steps: u32,
+    steps_done: u32,
+    output: Option<PathBuf>,
+    save: Option<PathBuf>,
+}
+
+/// Runs the physics chain for a fixed number of fixed timesteps
+/// without a window or renderer, then reports on the final state.
+pub fn run(config: SimConfig, args: Args) -> AppExit {
+    let steps = args.headless_steps(&config);
+    // Advance time by exactly one step per update, however long it takes.
+    let timestep = TimeUpdateStrategy::ManualDuration(config.timestep_duration());
+    let mut app = App::new();
+    app
+        .add_plugins((MinimalPlugins, LogPlugin::default()))
+        .add_plugins(SimulationPlugin { config })
+        .insert_resource(timestep)
+        .insert_resource(HeadlessRun { steps, steps_done: 0, output: args.output, save: args.save })
+        .add_systems(FixedUpdate, (finish, save_snapshot).chain().after(SimulationSet));
+    if let Some(dir) = args.record {
+        app.add_plugins(RecorderPlugin { dir, stride: args.record_stride });
+    }
+    if let Some(path) = args.load {
+        app.world_mut().send_event(LoadSnapshot(path));
+    }
+    app.run()
+}
+
+fn finish(
+    clock: Res<SimClock>,
+    mut run: ResMut<HeadlessRun>,
+    particles: Query<ParticleRecord>,
+    average_ek: Res<AverageEK>,
+    rng: Res<SimRng>,
+    config: Res<SimConfig>,
+    mut exit: EventWriter<AppExit>,
+) {
+    run.steps_done += 1;
+    if run.steps_done < run.steps {
+        return;
+    }
+
+    println!("seed:                 {}", rng.seed());
+    print_summary(&particles, run.steps_done, clock.elapsed, average_ek.0, &config);
+    if let Some(path) = &run.output {
+        if let Err(err) = recorder::write_csv(path, particles.iter()) {
+            eprintln!("could not write {}: {}", path.display(), err);
+            exit.send(AppExit::error());
+            return;
+        }
+    }
+    exit.send(AppExit::Success);
+}
+
+fn save_snapshot(run: Res<HeadlessRun>, mut save: EventWriter<SaveSnapshot>) {
+    if run.steps_done < run.steps {
+        return;
+    }
+    // Written at the end of this frame, before the app exits.
+    if let Some(path) = &run.save {
+        save.send(SaveSnapshot(path.clone()));
+    }
+}
+
+fn print_summary(
+    particles: &Query<ParticleRecord>,
+    steps: u32,
+    elapsed: f32,
+    average_ek: f32,
+    config: &SimConfig,
+) {
+    let count = particles.iter().len();
+    let mut density_sum = 0.0;
+    let mut density_min = f32::INFINITY;
+    let mut density_max = f32::NEG_INFINITY;
+    let mut density_error_sum = 0.0;
+    let mut max_speed = 0.0_f32;
+    let mut centre_of_mass = Vec2::ZERO;
+    for (
+        _,
+        ParticlePosition(x),
+        ParticleVelocity(v),
+        _,
+        ParticleDensity(density),
+        _,
+    ) in particles.iter() {
+        density_sum += density;
+        density_min = density_min.min(*density);
+        density_max = density_max.max(*density);
+        density_error_sum += (density - config.target_density).abs();
+        max_speed = max_speed.max(v.length());
+        centre_of_mass += *x;
+    }
+    let count_inv = 1.0 / count.max(1) as f32;
+
+    println!("particles:            {}", count);
+    println!("steps:                {}", steps);
+    println!("simulated time:       {:.3} s", elapsed);
+    println!("density mean:         {:.4}", density_sum * count_inv);
+    println!("density min / max:    {:.4} / {:.4}", density_min, density_max);
+    println!(
+        "mean density error:   {:.2}%",
+        100.0 * density_error_sum * count_inv / config.target_density,
+    );
+    println!("average EK:           {:.4}", average_ek);
+    println!("max speed:            {:.4}", max_speed);
+    println!(
+        "centre of mass:       ({:.4}, {:.4})",
+        centre_of_mass.x * count_inv, centre_of_mass.y * count_inv,
+    );
+}
@@ -1,17 +1,33 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, window::PrimaryWindow};
 
 use crate::color;
+use crate::config::SimConfig;
 use crate::consts_private::SCREEN_FACTOR;
+use crate::container::Container;
 use crate::particle::{
+    self,
     ParticleAcceleration,
     ParticleDensity,
     ParticlePosition,
     ParticlePressure,
     ParticleVelocity,
     PrevParticlePosition,
-    PHYSICAL_HALF_SIZE
+    StepStartPosition,
 };
-use crate::random;
+use crate::random::SimRng;
+use crate::snapshot::{LoadSnapshot, SaveSnapshot, SnapshotFile};
+
+// The color of the circle showing the mouse force's reach.
+const ATTRACT_COLOR: Color = Color::srgba(0.3, 0.8, 1.0, 0.8);
+const REPEL_COLOR: Color = Color::srgba(1.0, 0.4, 0.3, 0.8);
+
+/// The force the mouse applies to nearby particles, in physical space.
+/// Positive strengths attract, negative repel, and zero leaves particles alone.
+#[derive(Resource, Default)]
+pub struct InteractionForce {
+    pub point: Vec2,
+    pub strength: f32,
+}
 
 pub fn keypress(
     mut query: Query<(
@@ -19,12 +35,16 @@ pub fn keypress(
         &mut ParticlePressure,
         &mut PrevParticlePosition,
         &mut ParticlePosition,
+        &mut StepStartPosition,
         &mut ParticleVelocity,
         &mut ParticleAcceleration,
         &mut Transform,
         &MeshMaterial2d<ColorMaterial>
     )>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    mut rng: ResMut<SimRng>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
 ) {
     // Re-spawn particles on spacebar.
     for (
@@ -32,6 +52,7 @@ pub fn keypress(
         mut pressure,
         mut prev_position,
         mut position,
+        mut step_start,
         mut velocity,
         mut acceleration,
         mut transform,
@@ -40,13 +61,64 @@ pub fn keypress(
         density.0 = 0.0;
         pressure.0 = 0.0;
         prev_position.0 = None;
-        let (x, y) = random::point_in_box(PHYSICAL_HALF_SIZE);
-        position.0 = Vec2{ x, y };
+        let pose = &container.pose;
+        position.0 = particle::spawn_position(&mut rng, pose, pose.half_size, &config);
+        step_start.0 = position.0;
         velocity.0 = Vec2::ZERO;
         acceleration.0 = Vec2::ZERO;
-        transform.translation.x = x * SCREEN_FACTOR;
-        transform.translation.y = y * SCREEN_FACTOR;
+        transform.translation.x = position.0.x * SCREEN_FACTOR;
+        transform.translation.y = position.0.y * SCREEN_FACTOR;
         materials.get_mut(color_handle).unwrap().color =
             color::for_velocity(0.0);
     }
 }
+
+pub fn save_snapshot(file: Res<SnapshotFile>, mut events: EventWriter<SaveSnapshot>) {
+    events.send(SaveSnapshot(file.0.clone()));
+}
+
+pub fn load_snapshot(file: Res<SnapshotFile>, mut events: EventWriter<LoadSnapshot>) {
+    events.send(LoadSnapshot(file.0.clone()));
+}
+
+pub fn toggle_near_density(mut config: ResMut<SimConfig>) {
+    config.near_density = !config.near_density;
+    info!("near-density {}", if config.near_density { "on" } else { "off" });
+}
+
+/// Left-drag pulls particles towards the cursor, right-drag pushes them away.
+pub fn mouse_force(
+    buttons: Res<ButtonInput<MouseButton>>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    camera: Single<(&Camera, &GlobalTransform)>,
+    mut force: ResMut<InteractionForce>,
+    config: Res<SimConfig>,
+) {
+    let (camera, camera_transform) = *camera;
+    let cursor = window.cursor_position()
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor).ok());
+    force.strength = match cursor {
+        Some(_) if buttons.pressed(MouseButton::Left) => config.interaction_strength,
+        Some(_) if buttons.pressed(MouseButton::Right) => -config.interaction_strength,
+        _ => 0.0,
+    };
+    if let Some(cursor) = cursor {
+        force.point = cursor / SCREEN_FACTOR;
+    }
+}
+
+pub fn draw_force_radius(
+    mut gizmos: Gizmos,
+    force: Res<InteractionForce>,
+    config: Res<SimConfig>,
+) {
+    if force.strength == 0.0 {
+        return;
+    }
+    let color = if force.strength > 0.0 { ATTRACT_COLOR } else { REPEL_COLOR };
+    gizmos.circle_2d(
+        Isometry2d::from_translation(force.point * SCREEN_FACTOR),
+        config.interaction_radius * SCREEN_FACTOR,
+        color,
+    );
+}
@@ -1,96 +1,575 @@
+use std::sync::Arc;
+
+use bevy::prelude::Resource;
 use glam::f32::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimConfig;
+
+/// A smoothing kernel `W`, which should integrate to one over the disk of
+/// radius `support_radius` and be zero outside it.
+/// Implement this to use a kernel of your own, see `Kernels`.
+pub trait Kernel: Send + Sync {
+    /// The kernel at a squared distance from its centre.
+    fn value(&self, displacement_squared: f32) -> f32;
+
+    /// Computes the gradient of the kernel at `displacement`, negated so that
+    /// it points away from the centre.
+    /// `fallback_dir` is only called if the gradient direction is degenerate,
+    /// and gives a random vector within the unit disk.
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2;
+
+    /// Computes the Laplacian of the kernel at a squared distance from the centre.
+    fn laplacian(&self, displacement_squared: f32) -> f32;
 
-use crate::consts::SMOOTHING_RADIUS;
-use crate::random;
+    /// How far from the centre the kernel reaches.
+    fn support_radius(&self) -> f32;
 
-#[allow(dead_code)]
-pub enum Kernel {
+    /// The density a particle contributes at its own position.
+    fn self_value(&self) -> f32 {
+        self.value(0.0)
+    }
+}
+
+/// The built in kernels, as named in config files.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KernelKind {
     Smooth6,
     Spiky2,
+    // Steeper than `Spiky2`, as used for near-density.
+    Spiky3,
+    // Monaghan's cubic B-spline, squeezed to fit within the smoothing radius.
+    CubicSpline,
+    WendlandC2,
+    WendlandC4,
+    // Müller's Poly6, which in 2D is the same as `Smooth6`.
+    Poly6,
+    // Müller's viscosity kernel, rederived for 2D so its Laplacian is
+    // proportional to `smoothing_radius - r`. It is infinite at the centre,
+    // so it can't be used for density.
+    Viscosity,
 }
 
-impl Kernel {
-    pub fn influence(self, displacement_squared: f32) -> f32 {
+impl KernelKind {
+    pub fn build(self, smoothing_radius: f32) -> Arc<dyn Kernel> {
         match self {
-            Kernel::Smooth6 => smooth6(displacement_squared),
-            Kernel::Spiky2 => spiky2(displacement_squared),
+            KernelKind::Smooth6 | KernelKind::Poly6 => Arc::new(Smooth6::new(smoothing_radius)),
+            KernelKind::Spiky2 => Arc::new(Spiky2::new(smoothing_radius)),
+            KernelKind::Spiky3 => Arc::new(Spiky3::new(smoothing_radius)),
+            KernelKind::CubicSpline => Arc::new(CubicSpline::new(smoothing_radius)),
+            KernelKind::WendlandC2 => Arc::new(WendlandC2::new(smoothing_radius)),
+            KernelKind::WendlandC4 => Arc::new(WendlandC4::new(smoothing_radius)),
+            KernelKind::Viscosity => Arc::new(Viscosity::new(smoothing_radius)),
         }
     }
+}
 
-    pub fn gradient(self, displacement: Vec2) -> Vec2 {
-        match self {
-            Kernel::Smooth6 => grad_smooth6(displacement),
-            Kernel::Spiky2 => grad_spiky2(displacement),
+/// The kernels the simulation uses, built from the config.
+/// To use custom kernels, insert this after adding the simulation plugin.
+#[derive(Resource, Clone)]
+pub struct Kernels {
+    pub density: Arc<dyn Kernel>,
+    pub viscosity: Arc<dyn Kernel>,
+    // Measures near-density, so should be steeper than `density`.
+    pub near: Arc<dyn Kernel>,
+    // Smooths the colour field surface normals are measured from, and PBF
+    // velocities, so needs to be bounded with a Laplacian finite at the centre.
+    pub surface: Arc<dyn Kernel>,
+}
+
+impl Kernels {
+    pub fn new(config: &SimConfig) -> Self {
+        Self {
+            density: config.density_kernel.build(config.smoothing_radius),
+            viscosity: config.viscosity_kernel.build(config.smoothing_radius),
+            near: KernelKind::Spiky3.build(config.smoothing_radius),
+            surface: KernelKind::Poly6.build(config.smoothing_radius),
         }
     }
+
+    /// How far apart particles can be and still influence each other.
+    pub fn support_radius(&self) -> f32 {
+        self.density.support_radius()
+            .max(self.viscosity.support_radius())
+            .max(self.near.support_radius())
+            .max(self.surface.support_radius())
+    }
 }
 
-const SMOOTHING_RADIUS_INV: f32 = 1.0 / SMOOTHING_RADIUS;
-const SMOOTHING_RADIUS_2: f32 = SMOOTHING_RADIUS * SMOOTHING_RADIUS;
-#[allow(dead_code)]
-const SMOOTHING_RADIUS_2_INV: f32 = 1.0 / SMOOTHING_RADIUS_2;
+/// Powers of the smoothing radius the kernels are written in terms of.
+#[derive(Clone, Copy, Debug)]
+struct Radius {
+    h: f32,
+    h_inv: f32,
+    h_2: f32,
+    h_2_inv: f32,
+}
+
+impl Radius {
+    fn new(h: f32) -> Self {
+        let h_2 = h * h;
+        Self {
+            h,
+            h_inv: 1.0 / h,
+            h_2,
+            h_2_inv: 1.0 / h_2,
+        }
+    }
+}
 
-// Constants for smooth6 kernel.
-pub const SMOOTH6_FACTOR: f32 =
-    4.0 / (std::f32::consts::PI * SMOOTHING_RADIUS_2);
-const SMOOTH6_GRAD_FACTOR: f32 = 24.0 / (
-    std::f32::consts::PI * SMOOTHING_RADIUS_2 * SMOOTHING_RADIUS_2
-);
+#[derive(Clone, Copy, Debug)]
+pub struct Smooth6 {
+    radius: Radius,
+    factor: f32,
+    grad_factor: f32,
+}
 
-#[allow(dead_code)]
-fn smooth6(displacement_squared: f32) -> f32 {
-    if displacement_squared > SMOOTHING_RADIUS_2 {
-        0.0
-    } else {
-        let value = 1.0 - displacement_squared * SMOOTHING_RADIUS_2_INV;
-        SMOOTH6_FACTOR * value * value * value
+impl Smooth6 {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        Self {
+            radius,
+            factor: 4.0 / (std::f32::consts::PI * radius.h_2),
+            grad_factor: 24.0 / (std::f32::consts::PI * radius.h_2 * radius.h_2),
+        }
     }
 }
 
-fn grad_smooth6(displacement: Vec2) -> Vec2 {
-    let mag_2 = displacement.length_squared();
-    if mag_2 > SMOOTHING_RADIUS_2 {
-        Vec2::ZERO
-    } else {
-        if mag_2 < std::f32::EPSILON {
-            let dir = random::vec_within_disk(1.0);
-            SMOOTH6_GRAD_FACTOR * dir
+impl Kernel for Smooth6 {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            0.0
+        } else {
+            let value = 1.0 - displacement_squared * self.radius.h_2_inv;
+            self.factor * value * value * value
+        }
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        let mag_2 = displacement.length_squared();
+        if mag_2 > self.radius.h_2 {
+            Vec2::ZERO
+        } else {
+            if mag_2 < std::f32::EPSILON {
+                let dir = fallback_dir();
+                self.grad_factor * dir
+            } else {
+                let value = 1.0 - mag_2 * self.radius.h_2_inv;
+                self.grad_factor * value * value * displacement
+            }
+        }
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            0.0
         } else {
-            let value = 1.0 - mag_2 * SMOOTHING_RADIUS_2_INV;
-            SMOOTH6_GRAD_FACTOR * value * value * displacement
+            let q_2 = displacement_squared * self.radius.h_2_inv;
+            -2.0 * self.grad_factor * (1.0 - q_2) * (1.0 - 3.0 * q_2)
         }
     }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
 }
 
-// Constants for spiky2 kernel.
-pub const SPIKY2_FACTOR: f32 = 6.0 / (std::f32::consts::PI * SMOOTHING_RADIUS_2);
-const SPIKY2_GRAD_FACTOR: f32 = 12.0 / (
-    std::f32::consts::PI * SMOOTHING_RADIUS_2 * SMOOTHING_RADIUS
-);
+#[derive(Clone, Copy, Debug)]
+pub struct Spiky2 {
+    radius: Radius,
+    factor: f32,
+    grad_factor: f32,
+}
 
-fn spiky2(displacement_squared: f32) -> f32 {
-    if displacement_squared > SMOOTHING_RADIUS_2 {
-        0.0
-    } else {
-        let distance = displacement_squared.sqrt();
-        let value = 1.0 - distance * SMOOTHING_RADIUS_INV;
-        SPIKY2_FACTOR * value * value
+impl Spiky2 {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        Self {
+            radius,
+            factor: 6.0 / (std::f32::consts::PI * radius.h_2),
+            grad_factor: 12.0 / (std::f32::consts::PI * radius.h_2 * radius.h),
+        }
     }
 }
 
-fn grad_spiky2(displacement: Vec2) -> Vec2 {
-    if displacement.length_squared() > SMOOTHING_RADIUS_2 {
-        Vec2::ZERO
-    } else {
+impl Kernel for Spiky2 {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            0.0
+        } else {
+            let distance = displacement_squared.sqrt();
+            let value = 1.0 - distance * self.radius.h_inv;
+            self.factor * value * value
+        }
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        if displacement.length_squared() > self.radius.h_2 {
+            Vec2::ZERO
+        } else {
+            let distance = displacement.length();
+            if distance < std::f32::EPSILON {
+                // If we are at the centre of influence, pick a random direction.
+                let dir = fallback_dir();
+                self.grad_factor * dir
+            } else {
+                self.grad_factor * (
+                    1.0 / distance - self.radius.h_inv
+                ) * displacement
+            }
+        }
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            0.0
+        } else {
+            // Unbounded at the centre, so stop just short of it.
+            let distance = displacement_squared.sqrt().max(f32::EPSILON);
+            self.grad_factor * self.radius.h_inv * (2.0 - self.radius.h / distance)
+        }
+    }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Spiky3 {
+    radius: Radius,
+    factor: f32,
+    grad_factor: f32,
+}
+
+impl Spiky3 {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        Self {
+            radius,
+            factor: 10.0 / (std::f32::consts::PI * radius.h_2),
+            grad_factor: 30.0 / (std::f32::consts::PI * radius.h_2 * radius.h),
+        }
+    }
+}
+
+impl Kernel for Spiky3 {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let value = 1.0 - displacement_squared.sqrt() * self.radius.h_inv;
+        self.factor * value * value * value
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        if displacement.length_squared() > self.radius.h_2 {
+            return Vec2::ZERO;
+        }
         let distance = displacement.length();
-        if distance < std::f32::EPSILON {
+        if distance < f32::EPSILON {
             // If we are at the centre of influence, pick a random direction.
-            let dir = random::vec_within_disk(1.0);
-            SPIKY2_GRAD_FACTOR * dir
+            let dir = fallback_dir();
+            return self.grad_factor * dir;
+        }
+        let value = 1.0 - distance * self.radius.h_inv;
+        self.grad_factor * value * value / distance * displacement
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        // Unbounded at the centre, so stop just short of it.
+        let distance = displacement_squared.sqrt().max(f32::EPSILON);
+        let value = 1.0 - distance * self.radius.h_inv;
+        self.grad_factor * value * (2.0 * self.radius.h_inv - value / distance)
+    }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
+}
+
+/// Written in terms of q = 2r / h.
+#[derive(Clone, Copy, Debug)]
+pub struct CubicSpline {
+    radius: Radius,
+    factor: f32,
+    grad_factor: f32,
+}
+
+impl CubicSpline {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        let factor = 40.0 / (7.0 * std::f32::consts::PI * radius.h_2);
+        Self {
+            radius,
+            factor,
+            grad_factor: 4.0 * factor * radius.h_2_inv,
+        }
+    }
+}
+
+impl Kernel for CubicSpline {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let q = 2.0 * displacement_squared.sqrt() * self.radius.h_inv;
+        if q < 1.0 {
+            self.factor * (1.0 - 1.5 * q * q + 0.75 * q * q * q)
+        } else {
+            let value = 2.0 - q;
+            self.factor * 0.25 * value * value * value
+        }
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        let mag_2 = displacement.length_squared();
+        if mag_2 > self.radius.h_2 {
+            return Vec2::ZERO;
+        }
+        if mag_2 < f32::EPSILON {
+            let dir = fallback_dir();
+            return 3.0 * self.grad_factor * dir;
+        }
+        let q = 2.0 * mag_2.sqrt() * self.radius.h_inv;
+        if q < 1.0 {
+            self.grad_factor * (3.0 - 2.25 * q) * displacement
+        } else {
+            let value = 2.0 - q;
+            self.grad_factor * 0.75 * value * value / q * displacement
+        }
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let q = 2.0 * displacement_squared.sqrt() * self.radius.h_inv;
+        if q < 1.0 {
+            self.grad_factor * (6.75 * q - 6.0)
         } else {
-            SPIKY2_GRAD_FACTOR * (
-                1.0 / distance - SMOOTHING_RADIUS_INV
-            ) * displacement
+            let value = 2.0 - q;
+            self.grad_factor * value * (1.5 - 0.75 * value / q)
+        }
+    }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
+}
+
+/// Written in terms of q = r / h.
+#[derive(Clone, Copy, Debug)]
+pub struct WendlandC2 {
+    radius: Radius,
+    factor: f32,
+    grad_factor: f32,
+}
+
+impl WendlandC2 {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        let factor = 7.0 / (std::f32::consts::PI * radius.h_2);
+        Self {
+            radius,
+            factor,
+            grad_factor: 20.0 * factor * radius.h_2_inv,
+        }
+    }
+}
+
+impl Kernel for WendlandC2 {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let q = displacement_squared.sqrt() * self.radius.h_inv;
+        let value = 1.0 - q;
+        self.factor * value * value * value * value * (1.0 + 4.0 * q)
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        let mag_2 = displacement.length_squared();
+        if mag_2 > self.radius.h_2 {
+            return Vec2::ZERO;
+        }
+        if mag_2 < f32::EPSILON {
+            let dir = fallback_dir();
+            return self.grad_factor * dir;
+        }
+        let value = 1.0 - mag_2.sqrt() * self.radius.h_inv;
+        self.grad_factor * value * value * value * displacement
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let q = displacement_squared.sqrt() * self.radius.h_inv;
+        let value = 1.0 - q;
+        -self.grad_factor * value * value * (2.0 - 5.0 * q)
+    }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
+}
+
+/// Written in terms of q = r / h.
+#[derive(Clone, Copy, Debug)]
+pub struct WendlandC4 {
+    radius: Radius,
+    factor: f32,
+    grad_factor: f32,
+}
+
+impl WendlandC4 {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        let factor = 9.0 / (std::f32::consts::PI * radius.h_2);
+        Self {
+            radius,
+            factor,
+            grad_factor: 56.0 / 3.0 * factor * radius.h_2_inv,
+        }
+    }
+}
+
+impl Kernel for WendlandC4 {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let q = displacement_squared.sqrt() * self.radius.h_inv;
+        let value = 1.0 - q;
+        let value_3 = value * value * value;
+        self.factor * value_3 * value_3 * (1.0 + 6.0 * q + 35.0 / 3.0 * q * q)
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        let mag_2 = displacement.length_squared();
+        if mag_2 > self.radius.h_2 {
+            return Vec2::ZERO;
+        }
+        if mag_2 < f32::EPSILON {
+            let dir = fallback_dir();
+            return self.grad_factor * dir;
+        }
+        let q = mag_2.sqrt() * self.radius.h_inv;
+        let value = 1.0 - q;
+        let value_5 = value * value * value * value * value;
+        self.grad_factor * value_5 * (1.0 + 5.0 * q) * displacement
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        let q = displacement_squared.sqrt() * self.radius.h_inv;
+        let value = 1.0 - q;
+        let value_4 = value * value * value * value;
+        -self.grad_factor * value_4 * (2.0 + 8.0 * q - 40.0 * q * q)
+    }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Viscosity {
+    radius: Radius,
+    factor: f32,
+}
+
+impl Viscosity {
+    pub fn new(smoothing_radius: f32) -> Self {
+        let radius = Radius::new(smoothing_radius);
+        Self {
+            radius,
+            factor: 40.0 / (
+                std::f32::consts::PI * radius.h_2 * radius.h_2 * radius.h
+            ),
+        }
+    }
+}
+
+impl Kernel for Viscosity {
+    fn value(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            return 0.0;
+        }
+        // Unbounded at the centre, so stop just short of it.
+        let distance = displacement_squared.sqrt().max(f32::EPSILON);
+        let Radius { h, h_2, h_inv, .. } = self.radius;
+        self.factor * (
+            0.25 * h * displacement_squared
+            - distance * displacement_squared / 9.0
+            - 5.0 / 36.0 * h * h_2
+            - h * h_2 / 6.0 * (distance * h_inv).ln()
+        )
+    }
+
+    fn gradient(&self, displacement: Vec2, fallback_dir: &dyn Fn() -> Vec2) -> Vec2 {
+        let mag_2 = displacement.length_squared();
+        if mag_2 > self.radius.h_2 {
+            return Vec2::ZERO;
+        }
+        let Radius { h, h_2, .. } = self.radius;
+        let distance = mag_2.sqrt();
+        if distance < f32::EPSILON {
+            // Unbounded at the centre, so push as hard as just short of it.
+            let dir = fallback_dir();
+            return self.factor * h * h_2 / (6.0 * f32::EPSILON) * dir;
+        }
+        self.factor * (h * h_2 / (6.0 * mag_2) - 0.5 * h + distance / 3.0) * displacement
+    }
+
+    fn laplacian(&self, displacement_squared: f32) -> f32 {
+        if displacement_squared > self.radius.h_2 {
+            0.0
+        } else {
+            self.factor * (self.radius.h - displacement_squared.sqrt())
+        }
+    }
+
+    fn support_radius(&self) -> f32 {
+        self.radius.h
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernels_integrate_to_one_in_2d() {
+        let h = 1.2;
+        let kinds = [
+            KernelKind::Smooth6,
+            KernelKind::Spiky2,
+            KernelKind::Spiky3,
+            KernelKind::CubicSpline,
+            KernelKind::WendlandC2,
+            KernelKind::WendlandC4,
+            KernelKind::Poly6,
+            KernelKind::Viscosity,
+        ];
+        for kind in kinds {
+            let kernel = kind.build(h);
+            // Sum rings of the disk, sampled at their middles.
+            let rings = 20_000;
+            let dr = h / rings as f32;
+            let integral: f32 = (0..rings)
+                .map(|i| {
+                    let r = (i as f32 + 0.5) * dr;
+                    kernel.value(r * r) * 2.0 * std::f32::consts::PI * r * dr
+                })
+                .sum();
+            assert!((integral - 1.0).abs() < 1e-3, "{:?} integrates to {}", kind, integral);
         }
     }
 }
@@ -0,0 +1,88 @@
+//! A particle-based fluid simulator for Bevy.
+//!
+//! Add a [`FluidSimPlugin`] to an app to simulate and draw the fluid.
+//! For the physics alone, e.g. without a window, use
+//! [`simulation::SimulationPlugin`] on top of `MinimalPlugins` instead.
+
+pub mod background;
+pub mod color;
+pub mod config;
+pub mod consts;
+pub mod container;
+mod consts_private;
+pub mod emitter;
+pub mod interaction;
+pub mod kernel;
+mod maths;
+pub mod obstacle;
+pub mod particle;
+pub mod pbf;
+pub mod pcisph;
+pub mod physics;
+pub mod random;
+pub mod recorder;
+pub mod replay;
+pub mod rewind;
+pub mod simulation;
+pub mod snapshot;
+pub mod spatial_hash;
+#[cfg(test)]
+mod test_utils;
+mod utils;
+
+use bevy::prelude::*;
+
+pub use config::SimConfig;
+pub use consts_private::SCREEN_FACTOR;
+pub use kernel::{Kernel, Kernels};
+pub use replay::{Replay, ReplayPlugin};
+pub use simulation::{SimulationPlugin, SimulationSet};
+
+/// Simulates the fluid and optionally draws it. Particles are drawn centred
+/// on the world origin, with one physical unit being `SCREEN_FACTOR` pixels.
+/// The app must provide its own camera.
+pub struct FluidSimPlugin {
+    pub config: SimConfig,
+    // Draw each particle as a circle colored by its speed.
+    pub render_particles: bool,
+    // Draw the density field as an image behind the particles.
+    pub render_density: bool,
+    // Play back a recorded run instead of simulating.
+    pub replay: Option<Replay>,
+}
+
+impl FluidSimPlugin {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            config,
+            render_particles: true,
+            render_density: true,
+            replay: None,
+        }
+    }
+}
+
+impl Plugin for FluidSimPlugin {
+    fn build(&self, app: &mut App) {
+        match &self.replay {
+            Some(replay) => app.add_plugins(ReplayPlugin { replay: replay.clone() }),
+            None => app.add_plugins(SimulationPlugin { config: self.config.clone() }),
+        };
+        if self.render_particles {
+            app.add_systems(Update, (
+                particle::add_meshes,
+                particle::update_transforms,
+                particle::update_colors,
+            ).chain())
+            .add_systems(Update, emitter::draw);
+        }
+        if self.render_particles || self.render_density {
+            app.add_systems(Startup, obstacle::spawn_meshes);
+        }
+        if self.render_density {
+            app
+                .add_systems(Startup, background::spawn)
+                .add_systems(Update, background::update);
+        }
+    }
+}
@@ -1,129 +1,169 @@
-mod background;
-mod color;
-mod consts;
-mod consts_private;
-mod interaction;
-mod kernel;
-mod maths;
-mod particle;
-mod physics;
-mod random;
+mod cli;
+mod headless;
 mod ui;
-mod utils;
 
 use bevy::{
     input::common_conditions::input_just_pressed,
     prelude::*,
-    render::{
-        render_asset::RenderAssetUsages,
-        render_resource::{
-            Extent3d,
-            TextureDimension,
-            TextureFormat,
-            TextureUsages,
-        }
-    },
 };
+use clap::Parser;
 
-use background::Background;
-use consts::{BOX_LINE_WIDTH, BOX_SIZE, PIXEL_SIZE, STARTUP_DAMPING};
-use consts_private::{BOX_LINE_CENTRE, BOX_SIZE_F, IMAGE_SIZE, WINDOW_SIZE_F};
-use physics::StartupDamping;
+use flow::recorder::RecorderPlugin;
+use flow::replay::{Playback, Replay};
+use flow::rewind::RewindPlugin;
+use flow::snapshot::{LoadSnapshot, SnapshotFile};
+use flow::container::{self, BoxSide};
+use flow::{interaction, FluidSimPlugin, SimConfig};
 use ui::*;
 
-fn main() {
-    App::new()
+fn exit_on_error<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
+
+fn main() -> AppExit {
+    let args = cli::Args::parse();
+    if let Some(dir) = &args.replay {
+        let replay = exit_on_error(Replay::load(dir));
+        return replay_app(replay).run();
+    }
+
+    let config = exit_on_error(args.load_config());
+    if args.headless {
+        return headless::run(config, args);
+    }
+
+    let mut app = window_app(&config);
+    app
+        .add_plugins(FluidSimPlugin::new(config))
+        .add_plugins(RewindPlugin { seconds: args.rewind })
+        .insert_resource(SnapshotFile(args.snapshot))
+        .add_systems(Update, (
+            interaction::keypress.run_if(input_just_pressed(KeyCode::Space)),
+            interaction::save_snapshot.run_if(input_just_pressed(KeyCode::F5)),
+            interaction::load_snapshot.run_if(input_just_pressed(KeyCode::F9)),
+            interaction::toggle_near_density.run_if(input_just_pressed(KeyCode::KeyN)),
+            (interaction::mouse_force, interaction::draw_force_radius).chain(),
+            container::controls,
+            container::update_outline,
+            ui::update,
+        ));
+    if let Some(dir) = args.record {
+        app.add_plugins(RecorderPlugin { dir, stride: args.record_stride });
+    }
+    if let Some(path) = args.load {
+        app.world_mut().send_event(LoadSnapshot(path));
+    }
+    app.run()
+}
+
+/// An app with a window and the scene around the fluid, but no fluid.
+fn window_app(config: &SimConfig) -> App {
+    let mut app = App::new();
+    app
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 title: "Fluid Box!".to_string(),
-                resolution: WINDOW_SIZE_F.into(),
+                resolution: config.window_size_f().into(),
                 ..Default::default()
             }),
             ..Default::default()
         }).set(ImagePlugin::default_nearest()))
         .insert_resource(UILastUpdate(0.0))
-        .insert_resource(StartupDamping(if STARTUP_DAMPING {0.0} else {1.0}))
-        .insert_resource(AverageEK(0.0))
-        .add_systems(Startup, (setup_scene, particle::spawn))
-        .add_systems(Update, (
-            (
-                physics::update_startup_damping.run_if(|| STARTUP_DAMPING),
-                particle::predict_positions,
-                particle::update_densities_and_pressures,
-                particle::update_accelerations,
-                particle::update_positions,
-                particle::update_colors,
-                background::update,
-            ).chain(),
-            interaction::keypress.run_if(input_just_pressed(KeyCode::Space)),
-            ui::update,
-        ))
-        .run();
+        .add_systems(Startup, setup_scene);
+    app
+}
+
+fn replay_app(replay: Replay) -> App {
+    let config = replay.config.clone();
+    let mut app = window_app(&config);
+    app
+        .add_plugins(FluidSimPlugin { replay: Some(replay), ..FluidSimPlugin::new(config) })
+        .add_systems(Update, (container::update_outline, ui::update_replay));
+    app
 }
 
 fn setup_scene(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut images: ResMut<Assets<Image>>,
+    playback: Option<Res<Playback>>,
+    config: Res<SimConfig>,
 ) {
     commands.spawn(Camera2d);
 
     // Set up bounding box outline.
+    let box_size = config.box_size_f();
+    let box_line_centre = config.box_line_centre();
     let vertical = meshes.add(
         Rectangle::from_size(Vec2 {
-            x: BOX_LINE_WIDTH,
-            y: BOX_SIZE_F.1 + 2.0 * BOX_LINE_WIDTH,
+            x: config.box_line_width,
+            y: box_size.y + 2.0 * config.box_line_width,
         }));
     let horizontal = meshes.add(
         Rectangle::from_size(Vec2 {
-            x: BOX_SIZE_F.0 + 2.0 * BOX_LINE_WIDTH,
-            y: BOX_LINE_WIDTH
+            x: box_size.x + 2.0 * config.box_line_width,
+            y: config.box_line_width
         }));
 
     let box_color= materials.add(Color::WHITE);
+    // Periodic sides are open, so draw them dimmed.
+    let open_color = materials.add(Color::srgb(0.3, 0.3, 0.3));
+    let side_color = |periodic: bool| {
+        MeshMaterial2d(if periodic { open_color.clone() } else { box_color.clone() })
+    };
 
     // Left side.
     commands.spawn((
         Mesh2d(vertical.clone()),
-        MeshMaterial2d(box_color.clone()),
+        side_color(config.periodic.0),
         Transform::from_xyz(
-            -BOX_LINE_CENTRE.0, 0.0, 0.0
+            -box_line_centre.x, 0.0, 0.0
         ),
+        BoxSide { normal: Vec2::NEG_X },
     ));
     // Right side.
     commands.spawn((
         Mesh2d(vertical.clone()),
-        MeshMaterial2d(box_color.clone()),
+        side_color(config.periodic.0),
         Transform::from_xyz(
-            BOX_LINE_CENTRE.0, 0.0, 0.0
+            box_line_centre.x, 0.0, 0.0
         ),
+        BoxSide { normal: Vec2::X },
     ));
     // Bottom side.
     commands.spawn((
         Mesh2d(horizontal.clone()),
-        MeshMaterial2d(box_color.clone()),
+        side_color(config.periodic.1),
         Transform::from_xyz(
-            0.0, -BOX_LINE_CENTRE.1, 0.0
+            0.0, -box_line_centre.y, 0.0
         ),
+        BoxSide { normal: Vec2::NEG_Y },
     ));
     // Top side.
     commands.spawn((
         Mesh2d(horizontal.clone()),
-        MeshMaterial2d(box_color.clone()),
+        side_color(config.periodic.1),
         Transform::from_xyz(
-            0.0, BOX_LINE_CENTRE.1, 0.0
+            0.0, box_line_centre.y, 0.0
         ),
+        BoxSide { normal: Vec2::Y },
     ));
 
     // Set up frame rate text.
+    let labels = match playback {
+        Some(_) => ["FrameRate: ", ", Particles: ", ", Time: ", ", Frame: ", ", Speed: "],
+        None => ["FrameRate: ", ", Particles: ", ", Average EK: ", ", Damping: ", ", dt: "],
+    };
     let font = TextFont {
         font_size: FRAME_RATE_FONT_SIZE,
         ..default()
     };
     commands
         .spawn((
-            Text::new("FrameRate: "),
+            Text::new(labels[0]),
             font.clone(),
             TextColor(Color::WHITE),
             UI,
@@ -141,7 +181,27 @@ fn setup_scene(
                 TextColor(Color::WHITE),
             ));
             parent.spawn((
-                TextSpan::from(", Average EK: "),
+                TextSpan::from(labels[1]),
+                font.clone(),
+                TextColor(Color::WHITE),
+            ));
+            parent.spawn((
+                TextSpan::default(),
+                font.clone(),
+                TextColor(Color::WHITE),
+            ));
+            parent.spawn((
+                TextSpan::from(labels[2]),
+                font.clone(),
+                TextColor(Color::WHITE),
+            ));
+            parent.spawn((
+                TextSpan::default(),
+                font.clone(),
+                TextColor(Color::WHITE),
+            ));
+            parent.spawn((
+                TextSpan::from(labels[3]),
                 font.clone(),
                 TextColor(Color::WHITE),
             ));
@@ -151,7 +211,7 @@ fn setup_scene(
                 TextColor(Color::WHITE),
             ));
             parent.spawn((
-                TextSpan::from(", Damping: "),
+                TextSpan::from(labels[4]),
                 font.clone(),
                 TextColor(Color::WHITE),
             ));
@@ -161,36 +221,4 @@ fn setup_scene(
                 TextColor(Color::WHITE),
             ));
         });
-
-    // Set up background image texture.
-    let mut image = Image::new_fill(
-        Extent3d {
-            width: IMAGE_SIZE.0,
-            height: IMAGE_SIZE.1,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        &[0, 0, 0, 255],
-        TextureFormat::Rgba8Unorm,
-        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
-    );
-    image.texture_descriptor.usage =
-        TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::RENDER_ATTACHMENT;
-
-    commands.spawn((
-        Sprite {
-            image: images.add(image),
-            custom_size: Some(Vec2::new(
-                (BOX_SIZE.0 / PIXEL_SIZE) as f32,
-                (BOX_SIZE.1 / PIXEL_SIZE) as f32,
-            )),
-            ..Default::default()
-        },
-        Transform::default().with_scale(Vec3::new(
-            PIXEL_SIZE as f32,
-            PIXEL_SIZE as f32,
-            0.0,
-        )),
-        Background,
-    ));
 }
@@ -0,0 +1,243 @@
+use bevy::{
+    prelude::*,
+    render::{mesh::{Indices, PrimitiveTopology}, render_asset::RenderAssetUsages},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimConfig;
+use crate::consts_private::SCREEN_FACTOR;
+
+const OBSTACLE_COLOR: Color = Color::srgb(0.45, 0.45, 0.5);
+// Step used to estimate surface normals from the distance field.
+const NORMAL_EPSILON: f32 = 1e-3;
+
+/// A static obstacle inside the box, in physical space.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Obstacle {
+    Circle {
+        centre: (f32, f32),
+        radius: f32,
+    },
+    Rect {
+        centre: (f32, f32),
+        size: (f32, f32),
+        // Anticlockwise rotation, in degrees.
+        #[serde(default)]
+        angle: f32,
+    },
+    Capsule {
+        start: (f32, f32),
+        end: (f32, f32),
+        radius: f32,
+    },
+    // Vertices in order around the outline, which may be concave.
+    Polygon {
+        points: Vec<(f32, f32)>,
+    },
+}
+
+impl Obstacle {
+    /// The signed distance from `point` to the surface,
+    /// negative inside the obstacle.
+    pub fn sdf(&self, point: Vec2) -> f32 {
+        match self {
+            Obstacle::Circle { centre, radius } => {
+                (point - Vec2::from(*centre)).length() - radius
+            }
+            Obstacle::Rect { centre, size, angle } => {
+                let local = Vec2::from_angle(-angle.to_radians())
+                    .rotate(point - Vec2::from(*centre));
+                let q = local.abs() - 0.5 * Vec2::from(*size);
+                q.max(Vec2::ZERO).length() + q.x.max(q.y).min(0.0)
+            }
+            Obstacle::Capsule { start, end, radius } => {
+                let (a, b) = (Vec2::from(*start), Vec2::from(*end));
+                let ab = b - a;
+                let t = if ab.length_squared() > 0.0 {
+                    ((point - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                (point - (a + t * ab)).length() - radius
+            }
+            Obstacle::Polygon { points } => polygon_sdf(points, point),
+        }
+    }
+
+    /// The outward unit normal of the surface nearest `point`.
+    pub fn normal(&self, point: Vec2) -> Vec2 {
+        let dx = Vec2::new(NORMAL_EPSILON, 0.0);
+        let dy = Vec2::new(0.0, NORMAL_EPSILON);
+        Vec2::new(
+            self.sdf(point + dx) - self.sdf(point - dx),
+            self.sdf(point + dy) - self.sdf(point - dy),
+        ).normalize_or(Vec2::Y)
+    }
+
+    fn mesh(&self) -> Mesh {
+        let scale = |point: &(f32, f32)| Vec2::from(*point) * SCREEN_FACTOR;
+        match self {
+            Obstacle::Circle { radius, .. } => Circle::new(radius * SCREEN_FACTOR).into(),
+            Obstacle::Rect { size, .. } => Rectangle::from_size(scale(size)).into(),
+            Obstacle::Capsule { start, end, radius } => Capsule2d::new(
+                radius * SCREEN_FACTOR,
+                (scale(end) - scale(start)).length(),
+            ).into(),
+            Obstacle::Polygon { points } => {
+                let points: Vec<Vec2> = points.iter().map(scale).collect();
+                let positions: Vec<[f32; 3]> =
+                    points.iter().map(|point| [point.x, point.y, 0.0]).collect();
+                Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+                    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+                    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; points.len()])
+                    .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; points.len()])
+                    .with_inserted_indices(Indices::U32(triangulate(&points)))
+            }
+        }
+    }
+
+    /// Where to draw the mesh from `mesh`, in screen space.
+    fn transform(&self) -> Transform {
+        let at = |point: Vec2, angle: f32| {
+            Transform::from_translation((point * SCREEN_FACTOR).extend(0.5))
+                .with_rotation(Quat::from_rotation_z(angle))
+        };
+        match self {
+            Obstacle::Circle { centre, .. } => at((*centre).into(), 0.0),
+            Obstacle::Rect { centre, angle, .. } => at((*centre).into(), angle.to_radians()),
+            Obstacle::Capsule { start, end, .. } => {
+                let (a, b) = (Vec2::from(*start), Vec2::from(*end));
+                // Capsule meshes run along the y axis.
+                at(0.5 * (a + b), Vec2::Y.angle_to(b - a))
+            }
+            Obstacle::Polygon { .. } => at(Vec2::ZERO, 0.0),
+        }
+    }
+}
+
+/// Distance to the nearest edge, made negative inside by the winding number,
+/// so concave outlines work too.
+fn polygon_sdf(points: &[(f32, f32)], point: Vec2) -> f32 {
+    let mut distance_squared = f32::INFINITY;
+    let mut inside = false;
+    for (i, a) in points.iter().enumerate() {
+        let (a, b) = (Vec2::from(*a), Vec2::from(points[(i + 1) % points.len()]));
+        let edge = b - a;
+        let t = ((point - a).dot(edge) / edge.length_squared()).clamp(0.0, 1.0);
+        distance_squared = distance_squared.min((point - (a + t * edge)).length_squared());
+        // Count crossings of a ray from the point along +x.
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < a.x + (point.y - a.y) / (b.y - a.y) * edge.x
+        {
+            inside = !inside;
+        }
+    }
+    let distance = distance_squared.sqrt();
+    if inside { -distance } else { distance }
+}
+
+/// Splits a simple polygon into triangles by ear clipping,
+/// returning indices into `points`.
+fn triangulate(points: &[Vec2]) -> Vec<u32> {
+    // Ear tests assume an anticlockwise outline.
+    let area: f32 = points.iter().enumerate()
+        .map(|(i, a)| a.perp_dot(points[(i + 1) % points.len()]))
+        .sum();
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    if area < 0.0 {
+        remaining.reverse();
+    }
+
+    let mut indices = Vec::with_capacity(3 * points.len().saturating_sub(2));
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let (a, b, c) = (
+                points[remaining[(i + n - 1) % n]],
+                points[remaining[i]],
+                points[remaining[(i + 1) % n]],
+            );
+            // Convex corner with no other vertex inside it.
+            (b - a).perp_dot(c - b) > 0.0 && remaining.iter().all(|&j| {
+                let p = points[j];
+                p == a || p == b || p == c
+                    || (b - a).perp_dot(p - a) < 0.0
+                    || (c - b).perp_dot(p - b) < 0.0
+                    || (a - c).perp_dot(p - c) < 0.0
+            })
+        // Degenerate outlines have no ears, so clip anyway rather than loop forever.
+        }).unwrap_or(0);
+        indices.extend([
+            remaining[(ear + n - 1) % n],
+            remaining[ear],
+            remaining[(ear + 1) % n],
+        ].map(|i| i as u32));
+        remaining.remove(ear);
+    }
+    indices.extend(remaining.iter().map(|&i| i as u32));
+    indices
+}
+
+/// Draws each obstacle as a mesh.
+pub fn spawn_meshes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    config: Res<SimConfig>,
+) {
+    let material = materials.add(OBSTACLE_COLOR);
+    for obstacle in &config.obstacles {
+        commands.spawn((
+            Mesh2d(meshes.add(obstacle.mesh())),
+            MeshMaterial2d(material.clone()),
+            obstacle.transform(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The polygon from the README, a square with a notch cut into its top.
+    const NOTCHED: [(f32, f32); 5] = [(3.0, 1.0), (5.0, 1.0), (5.0, 3.0), (4.0, 1.8), (3.0, 3.0)];
+
+    #[test]
+    fn concave_polygon_sdf_has_the_right_sign_and_distance() {
+        let cases = [
+            // In the notch, nearest the slanted edge on the right.
+            (Vec2::new(4.0, 2.5), 0.7 / 2.44_f32.sqrt()),
+            // In the left prong, nearest the slanted edge on the left.
+            (Vec2::new(3.5, 2.0), -0.4 / 2.44_f32.sqrt()),
+            // Under the notch, nearest the bottom edge.
+            (Vec2::new(4.0, 1.3), -0.3),
+            // Beyond the bottom left corner.
+            (Vec2::ZERO, 10.0_f32.sqrt()),
+        ];
+        for (point, expected) in cases {
+            let distance = polygon_sdf(&NOTCHED, point);
+            assert!((distance - expected).abs() < 1e-5, "{}: {} != {}", point, distance, expected);
+        }
+    }
+
+    #[test]
+    fn triangulation_covers_the_polygon() {
+        let points: Vec<Vec2> = NOTCHED.iter().map(|point| Vec2::from(*point)).collect();
+        let mut clockwise = points.clone();
+        clockwise.reverse();
+        for points in [points, clockwise] {
+            let indices = triangulate(&points);
+            assert_eq!(indices.len(), 3 * (points.len() - 2));
+            // No triangle overlaps the notch, so together they have the polygon's area.
+            let area: f32 = indices
+                .chunks(3)
+                .map(|triangle| {
+                    let [a, b, c] = [0, 1, 2].map(|i| points[triangle[i] as usize]);
+                    0.5 * (b - a).perp_dot(c - a).abs()
+                })
+                .sum();
+            assert!((area - 2.8).abs() < 1e-5, "area {}", area);
+        }
+    }
+}
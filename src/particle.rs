@@ -1,11 +1,24 @@
+use bevy::ecs::query::{QueryData, QueryFilter, QueryItem};
 use bevy::prelude::*;
 
 use crate::color;
-use crate::consts::*;
-use crate::consts_private::*;
+use crate::config::SimConfig;
+use crate::container::{Container, ContainerPose};
+use crate::consts_private::SCREEN_FACTOR;
+use crate::interaction::InteractionForce;
+use crate::kernel::Kernels;
 use crate::physics::{self, StartupDamping};
-use crate::random;
-use crate::AverageEK;
+use crate::random::{self, SimRng};
+use crate::simulation::{AverageEK, SimClock};
+use crate::spatial_hash::{PositionHash, SpatialHash};
+
+// How many random positions to try for each particle before giving up on
+// avoiding obstacles.
+const MAX_SPAWN_TRIES: u32 = 100;
+
+// How long a particle's surface normal must be for it to count as being on
+// the surface, and so have a curvature.
+const SURFACE_NORMAL_THRESHOLD: f32 = 0.1;
 
 #[derive(Component)]
 pub struct ParticleDensity(pub f32);
@@ -13,6 +26,36 @@ pub struct ParticleDensity(pub f32);
 #[derive(Component)]
 pub struct ParticlePressure(pub f32);
 
+// Density measured with the steeper near kernel, see `SimConfig::near_density`.
+#[derive(Component)]
+pub struct ParticleNearDensity(pub f32);
+
+// Always pushes particles apart, unlike `ParticlePressure`.
+#[derive(Component)]
+pub struct ParticleNearPressure(pub f32);
+
+// Points out of the fluid, with a length of about one at the surface
+// and zero deep inside, see `SimConfig::surface_tension`.
+#[derive(Component)]
+pub struct ParticleSurfaceNormal(pub Vec2);
+
+// The curvature of the surface through a particle, positive where it bulges out.
+#[derive(Component)]
+pub struct ParticleCurvature(pub f32);
+
+// How far along its density constraint gradient the PBF solver moves a particle.
+#[derive(Component)]
+pub struct ParticleLambda(pub f32);
+
+// How far the PBF solver moves a particle in the current iteration.
+#[derive(Component)]
+pub struct ParticlePositionCorrection(pub Vec2);
+
+// The acceleration due to everything but pressure, which the PCISPH solver
+// keeps fixed while it solves for pressures.
+#[derive(Component)]
+pub struct ParticleNonPressureAcceleration(pub Vec2);
+
 #[derive(Component)]
 pub struct ParticlePosition(pub Vec2);
 
@@ -22,6 +65,11 @@ pub struct PrevParticlePosition(pub Option<Vec2>);
 #[derive(Component)]
 pub struct PredictedParticlePosition(pub Vec2);
 
+// The position at the start of the latest fixed timestep,
+// used to interpolate rendering between physics states.
+#[derive(Component)]
+pub struct StepStartPosition(pub Vec2);
+
 #[derive(Component)]
 pub struct ParticleVelocity(pub Vec2);
 
@@ -30,34 +78,97 @@ pub struct ParticleAcceleration(pub Vec2);
 
 pub fn spawn(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut rng: ResMut<SimRng>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
 ) {
-    let circle = meshes.add(Circle::new(PARTICLE_SCREEN_RADIUS));
+    for _ in 0..config.num_particles {
+        // Start in the middle of the container.
+        let extent = container.pose.half_size * 0.5;
+        let position = spawn_position(&mut rng, &container.pose, extent, &config);
+        commands.spawn(new_particle(position, Vec2::ZERO));
+    }
+}
 
-    for _ in 0..NUM_PARTICLES {
-        let (x, y) = random::point_in_box((
-            PHYSICAL_HALF_SIZE.0 * 0.5,
-            PHYSICAL_HALF_SIZE.1 * 0.5,
-        ));
+/// A random position up to `extent` from the container's centre along each
+/// of its axes.
+pub fn spawn_position(
+    rng: &mut SimRng,
+    pose: &ContainerPose,
+    extent: Vec2,
+    config: &SimConfig,
+) -> Vec2 {
+    let radius = config.particle_radius();
+    // Don't start particles inside obstacles, if there's room elsewhere.
+    let mut tries = 0;
+    loop {
+        let (x, y) = random::point_in_box(rng.rng(), (extent.x, extent.y));
+        let position = pose.to_world(Vec2 {x, y});
+        let clear = config.obstacles
+            .iter()
+            .all(|obstacle| obstacle.sdf(position) > radius);
+        tries += 1;
+        if clear || tries == MAX_SPAWN_TRIES {
+            break position;
+        }
+    }
+}
+
+/// The physical components of a particle which has just appeared.
+pub fn new_particle(position: Vec2, velocity: Vec2) -> impl Bundle {
+    (
+        ParticleDensity(0.0),
+        ParticlePressure(0.0),
+        ParticleNearDensity(0.0),
+        ParticleNearPressure(0.0),
+        ParticleSurfaceNormal(Vec2::ZERO),
+        ParticleCurvature(0.0),
+        ParticleLambda(0.0),
+        ParticlePositionCorrection(Vec2::ZERO),
+        ParticleNonPressureAcceleration(Vec2::ZERO),
+        PrevParticlePosition(None),
+        ParticlePosition(position),
+        StepStartPosition(position),
+        PredictedParticlePosition(position),
+        ParticleVelocity(velocity),
+        ParticleAcceleration(Vec2::ZERO),
+    )
+}
 
-        commands.spawn((
-            // Animation properties.
+/// Gives newly spawned particles the components needed to draw them.
+pub fn add_meshes(
+    mut commands: Commands,
+    particles: Query<(Entity, &ParticlePosition), Added<ParticlePosition>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut circle: Local<Option<Handle<Mesh>>>,
+    config: Res<SimConfig>,
+) {
+    let circle = circle
+        .get_or_insert_with(|| meshes.add(Circle::new(config.particle_screen_radius)));
+    for (entity, ParticlePosition(x)) in &particles {
+        commands.entity(entity).insert((
             Mesh2d(circle.clone()),
             MeshMaterial2d(materials.add(color::for_velocity(0.0))),
-            Transform::from_xyz(x * SCREEN_FACTOR, y * SCREEN_FACTOR, 1.0),
-            // Physical properties.
-            ParticleDensity(0.0),
-            ParticlePressure(0.0),
-            PrevParticlePosition(None),
-            ParticlePosition(Vec2 {x, y}),
-            PredictedParticlePosition(Vec2::ZERO),
-            ParticleVelocity(Vec2::ZERO),
-            ParticleAcceleration(Vec2::ZERO),
+            Transform::from_xyz(x.x * SCREEN_FACTOR, x.y * SCREEN_FACTOR, 1.0),
         ));
     }
 }
 
+/// Runs `f` on each item of `query`, spread across threads if `multithreaded`.
+/// Each call only writes to its own item, so the order doesn't change the result.
+pub fn for_each_particle<D: QueryData, F: QueryFilter>(
+    query: &mut Query<D, F>,
+    multithreaded: bool,
+    f: impl Fn(QueryItem<'_, D>) + Send + Sync + Clone,
+) {
+    if multithreaded {
+        query.par_iter_mut().for_each(f);
+    } else {
+        query.iter_mut().for_each(f);
+    }
+}
+
 pub fn predict_positions(
     mut particles: Query<(
         &PrevParticlePosition,
@@ -66,165 +177,301 @@ pub fn predict_positions(
         &ParticleVelocity,
         &ParticleAcceleration,
     )>,
+    clock: Res<SimClock>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
 ) {
-    // Use a constant timestep for position prediction.
-    const DT: f32 = 1.0 / 60.0;
-    for (
+    // Predict with the same timestep the positions will be updated with.
+    let dt = clock.dt;
+    let prev_dt = clock.prev_dt;
+    let predict = |(
         PrevParticlePosition(prev_x),
         ParticlePosition(x),
         mut next_x,
         ParticleVelocity(v),
         ParticleAcceleration(a),
-    ) in &mut particles {
+    ): (
+        &PrevParticlePosition,
+        &ParticlePosition,
+        Mut<PredictedParticlePosition>,
+        &ParticleVelocity,
+        &ParticleAcceleration,
+    )| {
         let res = physics::verlet(
             prev_x,
             x,
             v,
             a,
-            DT,
+            dt,
+            prev_dt,
+            &container,
+            &config,
         );
         next_x.0 = res.x;
-    }
+    };
+    for_each_particle(&mut particles, config.multithreaded, predict);
 }
 
+type DensityItem<'a> = (
+    Entity,
+    &'a PredictedParticlePosition,
+    Mut<'a, ParticleDensity>,
+    Mut<'a, ParticlePressure>,
+    Mut<'a, ParticleNearDensity>,
+    Mut<'a, ParticleNearPressure>,
+);
+
 pub fn update_densities_and_pressures(
     mut particles: Query<(
         Entity,
         &PredictedParticlePosition,
         &mut ParticleDensity,
         &mut ParticlePressure,
+        &mut ParticleNearDensity,
+        &mut ParticleNearPressure,
     )>,
-    positions: Query<(Entity, &ParticlePosition)>,
+    hash: Res<PositionHash>,
     damping: Res<StartupDamping>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
 ) {
+    let kernel = &*kernels.density;
+    let density_factor = kernel.self_value();
     // For each particle.
-    for (
+    let update = |(
         entity,
         PredictedParticlePosition(pred_pos),
         mut density,
         mut pressure,
-    ) in &mut particles {
+        mut near_density,
+        mut near_pressure,
+    ): DensityItem| {
         // Start with the density from the edge of the container.
-        let mut sum = if EDGE_REPULSION {
-            physics::compute_edge_density(pred_pos)
+        let mut sum = if config.edge_repulsion {
+            physics::compute_edge_density(pred_pos, &container.pose, kernel, &config)
         } else {
             0.0
         };
-        // Sum the density contributions of all particles on that position.
-        for (other_entity, ParticlePosition(pos)) in positions.iter() {
+        let mut near_sum = 0.0;
+        // Sum the density contributions of all nearby particles, where they are now,
+        // on that position.
+        let neighbours = hash.periodic_neighbours(*pred_pos, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
             // Ignore the density contribution of this particle.
-            if other_entity == entity {
+            if neighbour.entity == entity {
                 continue;
             }
-            let displacement_squared = (pred_pos - pos).length_squared();
-            sum += DENSITY_KERNEL.influence(displacement_squared);
+            let displacement_squared = displacement.length_squared();
+            sum += kernel.value(displacement_squared);
+            if config.near_density {
+                near_sum += kernels.near.value(displacement_squared);
+            }
         }
         // Finally, add the density contribution of the particle itself.
-        density.0 = sum + DENSITY_FACTOR;
-        pressure.0 = physics::density_to_pressure(
-            density.0, damping.0 * PRESSURE_MULTIPLIER,
-        );
-    }
+        density.0 = sum + density_factor;
+        pressure.0 = physics::density_to_pressure(density.0, damping.0, &config);
+        // Near pressure has no target, so only ever repels.
+        near_density.0 = near_sum;
+        near_pressure.0 = near_sum * damping.0 * config.near_pressure_multiplier;
+    };
+    for_each_particle(&mut particles, config.multithreaded, update);
 }
 
+/// Estimates the surface normal and curvature at each particle from the
+/// gradient and Laplacian of a colour field, which is one inside the fluid.
+#[allow(clippy::too_many_arguments)]
+pub fn update_surface_normals(
+    mut normals: Query<(Entity, &mut ParticleSurfaceNormal, &mut ParticleCurvature)>,
+    particles: Query<(&PredictedParticlePosition, &ParticleDensity)>,
+    hash: Res<SpatialHash>,
+    clock: Res<SimClock>,
+    rng: Res<SimRng>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let kernel = &*kernels.surface;
+    // For each particle.
+    let update = |(
+        entity,
+        mut normal,
+        mut curvature,
+    ): (Entity, Mut<ParticleSurfaceNormal>, Mut<ParticleCurvature>)| {
+        let (PredictedParticlePosition(pos_x), ParticleDensity(density_x)) =
+            particles.get(entity).unwrap();
+
+        // The colour field gradient points into the fluid, so sum its negation.
+        let mut outward = Vec2::ZERO;
+        let mut laplacian = kernel.laplacian(0.0) / density_x;
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
+                continue;
+            }
+            let (_, ParticleDensity(density_i)) = particles.get(neighbour.entity).unwrap();
+            let fallback_dir = rng.pair_fallback_dir(clock.step, entity, neighbour.entity);
+            outward += kernel.gradient(displacement, &fallback_dir) / density_i;
+            laplacian += kernel.laplacian(displacement.length_squared()) / density_i;
+        }
+
+        // Scaled by the radius, so the normal's length doesn't depend on it.
+        normal.0 = kernel.support_radius() * outward;
+        curvature.0 = if normal.0.length() > SURFACE_NORMAL_THRESHOLD {
+            -laplacian / outward.length()
+        } else {
+            0.0
+        };
+    };
+    for_each_particle(&mut normals, config.multithreaded, update);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn update_accelerations(
     mut accelerations: Query<(Entity, &mut ParticleAcceleration)>,
     particles: Query<(
-        Entity,
         &PredictedParticlePosition,
         &ParticleVelocity,
         &ParticlePressure,
         &ParticleDensity,
+        &ParticleNearPressure,
+        &ParticleSurfaceNormal,
+        &ParticleCurvature,
     )>,
+    hash: Res<SpatialHash>,
     damping: Res<StartupDamping>,
+    clock: Res<SimClock>,
+    rng: Res<SimRng>,
+    force: Res<InteractionForce>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
 ) {
     // For each particle.
-    for (entity, mut acceleration) in &mut accelerations {
+    let update = |(entity, mut acceleration): (Entity, Mut<ParticleAcceleration>)| {
         // Get the predicted position of and density at that particle.
         let (
-            _,
             PredictedParticlePosition(pos_x),
             ParticleVelocity(vel_x),
             ParticlePressure(pressure_x),
             ParticleDensity(density_x),
+            ParticleNearPressure(near_pressure_x),
+            ParticleSurfaceNormal(normal_x),
+            ParticleCurvature(curvature_x),
         ) = particles.get(entity).unwrap();
 
         let mut pressure_gradient = Vec2::ZERO;
+        let mut near_pressure_gradient = Vec2::ZERO;
         let mut viscosity_force = Vec2::ZERO;
-        // Sum the acceleration contributions of all particles on that position.
-        for (
-            other_entity,
-            PredictedParticlePosition(pos_i),
-            ParticleVelocity(vel_i),
-            ParticlePressure(pressure_i),
-            ParticleDensity(density_i),
-        ) in particles.iter() {
-            if other_entity == entity {
+        // Sum the acceleration contributions of all nearby particles on that position.
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
                 continue;
             }
-            let displacement = pos_x - pos_i;
+            let (
+                _,
+                ParticleVelocity(vel_i),
+                ParticlePressure(pressure_i),
+                ParticleDensity(density_i),
+                ParticleNearPressure(near_pressure_i),
+                ..
+            ) = particles.get(neighbour.entity).unwrap();
+            let fallback_dir = rng.pair_fallback_dir(clock.step, entity, neighbour.entity);
 
             // Compute pressure gradient contribution.
             let shared_pressure = 0.5 * (pressure_x + pressure_i);
-            pressure_gradient += shared_pressure * DENSITY_KERNEL.gradient(displacement) / density_i;
+            let gradient = kernels.density.gradient(displacement, &fallback_dir);
+            pressure_gradient += shared_pressure * gradient / density_i;
+
+            // Compute near pressure gradient contribution.
+            if config.near_density {
+                let shared_near_pressure = 0.5 * (near_pressure_x + near_pressure_i);
+                let gradient = kernels.near.gradient(displacement, &fallback_dir);
+                near_pressure_gradient += shared_near_pressure * gradient / density_i;
+            }
 
-            // Compute viscosity contribution.
-            viscosity_force +=
-                (vel_i - vel_x) * VISCOSITY_KERNEL.influence(displacement.length_squared());
+            // Compute viscosity contribution, diffusing velocity through the kernel's Laplacian.
+            let laplacian = kernels.viscosity.laplacian(displacement.length_squared());
+            viscosity_force += (vel_i - vel_x) * laplacian / density_i;
         }
 
         // Compute acceleration.
-        let mut acc = damping.0 * pressure_gradient / density_x + viscosity_force * VISCOSITY;
-        if EDGE_REPULSION {
+        let mut acc =
+            damping.0 * pressure_gradient / density_x + viscosity_force * config.viscosity;
+        if config.near_density {
+            // Near pressures are already damped.
+            acc += near_pressure_gradient / density_x;
+        }
+        if config.surface_tension > 0.0 {
+            // Pull the surface in where it bulges out, and push it out where it dips in.
+            let colour_gradient = *normal_x / kernels.surface.support_radius();
+            acc -= config.surface_tension * curvature_x * colour_gradient / density_x;
+        }
+        if config.edge_repulsion {
             acc += physics::compute_edge_acceleration(
-                &pos_x,
+                pos_x,
                 *density_x,
-                damping.0 * PRESSURE_MULTIPLIER,
+                damping.0,
+                &container.pose,
+                &*kernels.density,
+                &config,
+                |axis| rng.keyed_vec_within_disk([clock.step, entity.to_bits(), axis]),
             );
         }
-        acc.y -= GRAVITY_FORCE;
+        if force.strength != 0.0 {
+            acc += physics::compute_interaction_acceleration(
+                pos_x, force.point, force.strength, config.interaction_radius,
+            );
+        }
+        acc.y -= config.gravity_force;
 
         acceleration.0 = acc;
-    }
+    };
+    for_each_particle(&mut accelerations, config.multithreaded, update);
 }
 
-// Compute the allowed range for the centre of a particle.
-pub const PHYSICAL_SIZE: (f32, f32) = (
-    BOX_SIZE_F.0 / SCREEN_FACTOR, BOX_SIZE_F.1 / SCREEN_FACTOR,
-);
-pub const PHYSICAL_HALF_SIZE: (f32, f32) = (
-    PHYSICAL_SIZE.0 * 0.5, PHYSICAL_SIZE.1 * 0.5,
-);
+pub fn store_step_start(
+    mut particles: Query<(&mut StepStartPosition, &ParticlePosition)>,
+) {
+    for (mut start, ParticlePosition(x)) in &mut particles {
+        start.0 = *x;
+    }
+}
 
 pub fn update_positions(
-    time: Res<Time>,
+    clock: Res<SimClock>,
     mut particles: Query<(
-        &mut Transform,
         &mut PrevParticlePosition,
         &mut ParticlePosition,
         &mut ParticleVelocity,
         &ParticleAcceleration,
+        &mut StepStartPosition,
     )>,
-    mut average_ek: ResMut<AverageEK>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
 ) {
-    let dt = time.delta_secs();
-    let mut ek_sum = 0.0;
-    for (
-        mut transform,
+    let dt = clock.dt;
+    let prev_dt = clock.prev_dt;
+    let update = |(
         mut prev_x,
         mut x,
         mut v,
         ParticleAcceleration(a),
-    ) in &mut particles {
+        mut step_start,
+    ): (
+        Mut<PrevParticlePosition>,
+        Mut<ParticlePosition>,
+        Mut<ParticleVelocity>,
+        &ParticleAcceleration,
+        Mut<StepStartPosition>,
+    )| {
         // Compute the next position.
 
         let res = physics::verlet(
-            &prev_x.0, &x.0, &v.0, a, dt,
+            &prev_x.0, &x.0, &v.0, a, dt, prev_dt, &container, &config,
         );
 
-        // Add to EK sum.
-        ek_sum += res.v.length_squared();
-
         // Set variables to new values.
         v.0 = res.v;
 
@@ -232,13 +479,74 @@ pub fn update_positions(
             prev_x.0 = Some(res.prev_x);
         }
         x.0 = res.x;
+        // Keep the rendered path from sweeping across the box after wrapping around.
+        step_start.0 += res.wrap;
+    };
+    for_each_particle(&mut particles, config.multithreaded, update);
+}
 
-        // Propagate position changes to transform to update animation.
-        transform.translation.x = res.x.x * SCREEN_FACTOR;
-        transform.translation.y = res.x.y * SCREEN_FACTOR;
+/// Moves particles to the positions a position based solver predicted for them,
+/// for PBF and PCISPH, wrapping them around periodic sides.
+pub fn commit_predicted_positions(
+    mut particles: Query<(
+        &mut PrevParticlePosition,
+        &mut ParticlePosition,
+        &PredictedParticlePosition,
+        &mut StepStartPosition,
+    )>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    let update = |(
+        mut prev_x,
+        mut x,
+        PredictedParticlePosition(next_x),
+        mut step_start,
+    ): (
+        Mut<PrevParticlePosition>,
+        Mut<ParticlePosition>,
+        &PredictedParticlePosition,
+        Mut<StepStartPosition>,
+    )| {
+        prev_x.0 = Some(x.0);
+        x.0 = *next_x;
+        let wrap = physics::wrap_periodic(&mut x.0, &container.pose, &config);
+        if let Some(prev_x) = &mut prev_x.0 {
+            *prev_x += wrap;
+        }
+        // Keep the rendered path from sweeping across the box after wrapping around.
+        step_start.0 += wrap;
+    };
+    for_each_particle(&mut particles, config.multithreaded, update);
+}
+
+/// Averages the kinetic energy per particle, once the solver has moved them.
+pub fn update_average_ek(
+    particles: Query<&ParticleVelocity>,
+    mut average_ek: ResMut<AverageEK>,
+) {
+    // Sum EK serially so the result doesn't depend on how work was split.
+    let ek_sum: f32 = particles
+        .iter()
+        .map(|ParticleVelocity(v)| v.length_squared())
+        .sum();
+    // Emitters and drains change the particle count during the run.
+    let count = particles.iter().len().max(1);
+    average_ek.0 = 0.5 * ek_sum / count as f32;
+}
+
+/// Propagates position changes to transforms to update animation,
+/// interpolating between the last two physics states.
+pub fn update_transforms(
+    time: Res<Time<Fixed>>,
+    mut particles: Query<(&mut Transform, &StepStartPosition, &ParticlePosition)>,
+) {
+    let alpha = time.overstep_fraction();
+    for (mut transform, StepStartPosition(start), ParticlePosition(x)) in &mut particles {
+        let rendered = start.lerp(*x, alpha);
+        transform.translation.x = rendered.x * SCREEN_FACTOR;
+        transform.translation.y = rendered.y * SCREEN_FACTOR;
     }
-    // Set EK value.
-    average_ek.0 = 0.5 * ek_sum / NUM_PARTICLES as f32;
 }
 
 pub fn update_colors(
@@ -0,0 +1,213 @@
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+
+use crate::config::SimConfig;
+use crate::container::Container;
+use crate::interaction::InteractionForce;
+use crate::kernel::Kernels;
+use crate::particle::{
+    for_each_particle,
+    ParticleAcceleration,
+    ParticleDensity,
+    ParticleLambda,
+    ParticlePosition,
+    ParticlePositionCorrection,
+    ParticleVelocity,
+    PredictedParticlePosition,
+};
+use crate::physics;
+use crate::random::SimRng;
+use crate::simulation::SimClock;
+use crate::spatial_hash::SpatialHash;
+
+// Each particle is pushed on by many constraints at once, so taking the whole
+// of every correction overshoots, and shakes the fluid apart if it's too tightly
+// packed to reach the target density.
+const CORRECTION_SCALE: f32 = 0.5;
+
+/// One Jacobi iteration of the density constraints, run `pbf_iterations`
+/// times per substep.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PbfIteration;
+
+/// Predicts where each particle would go under gravity and the mouse force alone.
+pub fn predict_positions(
+    mut particles: Query<(&ParticlePosition, &mut PredictedParticlePosition, &ParticleVelocity)>,
+    clock: Res<SimClock>,
+    force: Res<InteractionForce>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    let dt = clock.dt;
+    let predict = |(
+        ParticlePosition(x),
+        mut next_x,
+        ParticleVelocity(v),
+    ): (&ParticlePosition, Mut<PredictedParticlePosition>, &ParticleVelocity)| {
+        let mut acc = Vec2::new(0.0, -config.gravity_force);
+        if force.strength != 0.0 {
+            acc += physics::compute_interaction_acceleration(
+                x, force.point, force.strength, config.interaction_radius,
+            );
+        }
+        let v = *v + acc * dt;
+        next_x.0 = physics::confine(*x + v * dt, &container.pose, &config);
+    };
+    for_each_particle(&mut particles, config.multithreaded, predict);
+}
+
+/// Runs the constraint iterations.
+pub fn solve(world: &mut World) {
+    let iterations = world.resource::<SimConfig>().pbf_iterations;
+    for _ in 0..iterations {
+        world.run_schedule(PbfIteration);
+    }
+}
+
+/// Computes each particle's density and how far along its constraint
+/// gradient it must move to reach the target density.
+#[allow(clippy::too_many_arguments)]
+pub fn update_lambdas(
+    mut lambdas: Query<(Entity, &mut ParticleDensity, &mut ParticleLambda)>,
+    positions: Query<&PredictedParticlePosition>,
+    hash: Res<SpatialHash>,
+    clock: Res<SimClock>,
+    rng: Res<SimRng>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let kernel = &*kernels.density;
+    let target_density = config.target_density;
+    // For each particle.
+    let update = |(
+        entity,
+        mut density,
+        mut lambda,
+    ): (Entity, Mut<ParticleDensity>, Mut<ParticleLambda>)| {
+        let PredictedParticlePosition(pos_x) = positions.get(entity).unwrap();
+
+        let mut sum = kernel.self_value();
+        // The constraint gradient with respect to this particle,
+        // and the sum of its squared gradients with respect to each neighbour.
+        let mut gradient_x = Vec2::ZERO;
+        let mut gradient_squared_sum = 0.0;
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
+                continue;
+            }
+            let fallback_dir = rng.pair_fallback_dir(clock.step, entity, neighbour.entity);
+            sum += kernel.value(displacement.length_squared());
+            let gradient = kernel.gradient(displacement, &fallback_dir) / target_density;
+            gradient_x += gradient;
+            gradient_squared_sum += gradient.length_squared();
+        }
+
+        density.0 = sum;
+        // Only push particles apart, as pulling them together at the surface
+        // fights the walls once the box is too full to reach the target density.
+        let constraint = (sum / target_density - 1.0).max(0.0);
+        lambda.0 = -constraint / (
+            gradient_x.length_squared() + gradient_squared_sum + config.pbf_relaxation
+        );
+    };
+    for_each_particle(&mut lambdas, config.multithreaded, update);
+}
+
+/// Computes how far each particle moves to satisfy its own and its
+/// neighbours' constraints, plus an artificial pressure which stops
+/// particles clumping together where the density is low.
+#[allow(clippy::too_many_arguments)]
+pub fn update_corrections(
+    mut corrections: Query<(Entity, &mut ParticlePositionCorrection)>,
+    particles: Query<(&PredictedParticlePosition, &ParticleLambda)>,
+    hash: Res<SpatialHash>,
+    clock: Res<SimClock>,
+    rng: Res<SimRng>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let kernel = &*kernels.density;
+    let tensile_distance = config.pbf_tensile_distance * kernel.support_radius();
+    let tensile_reference = kernel.value(tensile_distance * tensile_distance);
+    // For each particle.
+    let update = |(entity, mut correction): (Entity, Mut<ParticlePositionCorrection>)| {
+        let (PredictedParticlePosition(pos_x), ParticleLambda(lambda_x)) =
+            particles.get(entity).unwrap();
+
+        let mut delta = Vec2::ZERO;
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
+                continue;
+            }
+            let (_, ParticleLambda(lambda_i)) = particles.get(neighbour.entity).unwrap();
+            let fallback_dir = rng.pair_fallback_dir(clock.step, entity, neighbour.entity);
+            let ratio = kernel.value(displacement.length_squared()) / tensile_reference;
+            let tensile = -config.pbf_tensile_strength * ratio.powi(config.pbf_tensile_exponent as i32);
+            // The kernel gradient points away from the neighbour, so negate it
+            // to get the constraint gradient.
+            delta -= (lambda_x + lambda_i + tensile) * kernel.gradient(displacement, &fallback_dir);
+        }
+        correction.0 = CORRECTION_SCALE * delta / config.target_density;
+    };
+    for_each_particle(&mut corrections, config.multithreaded, update);
+}
+
+pub fn apply_corrections(
+    mut particles: Query<(&mut PredictedParticlePosition, &ParticlePositionCorrection)>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    let apply = |(
+        mut next_x,
+        ParticlePositionCorrection(delta),
+    ): (Mut<PredictedParticlePosition>, &ParticlePositionCorrection)| {
+        next_x.0 = physics::confine(next_x.0 + *delta, &container.pose, &config);
+    };
+    for_each_particle(&mut particles, config.multithreaded, apply);
+}
+
+/// Sets velocities from how far particles moved, smoothed with XSPH viscosity.
+#[allow(clippy::too_many_arguments)]
+pub fn update_velocities(
+    mut velocities: Query<(Entity, &mut ParticleVelocity, &mut ParticleAcceleration)>,
+    particles: Query<(&ParticlePosition, &PredictedParticlePosition)>,
+    hash: Res<SpatialHash>,
+    clock: Res<SimClock>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let dt = clock.dt;
+    let velocity = |entity| {
+        let (ParticlePosition(x), PredictedParticlePosition(next_x)) = particles.get(entity).unwrap();
+        (*next_x - *x) / dt
+    };
+    // For each particle.
+    let update = |(
+        entity,
+        mut v,
+        mut acceleration,
+    ): (Entity, Mut<ParticleVelocity>, Mut<ParticleAcceleration>)| {
+        let (_, PredictedParticlePosition(pos_x)) = particles.get(entity).unwrap();
+        let vel_x = velocity(entity);
+
+        let mut smoothing = Vec2::ZERO;
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
+                continue;
+            }
+            // `Viscosity` is unbounded at the centre, so weight with the smooth kernel.
+            smoothing += (velocity(neighbour.entity) - vel_x)
+                * kernels.surface.value(displacement.length_squared());
+        }
+
+        let next_v = vel_x + config.viscosity * smoothing;
+        acceleration.0 = (next_v - v.0) / dt;
+        v.0 = next_v;
+    };
+    for_each_particle(&mut velocities, config.multithreaded, update);
+}
@@ -0,0 +1,297 @@
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+
+use crate::config::SimConfig;
+use crate::container::Container;
+use crate::interaction::InteractionForce;
+use crate::kernel::{Kernel, Kernels};
+use crate::particle::{
+    for_each_particle,
+    ParticleAcceleration,
+    ParticleDensity,
+    ParticleNonPressureAcceleration,
+    ParticlePosition,
+    ParticlePressure,
+    ParticleVelocity,
+    PredictedParticlePosition,
+};
+use crate::physics;
+use crate::random::SimRng;
+use crate::simulation::SimClock;
+use crate::spatial_hash::SpatialHash;
+
+// The furthest, in smoothing radii, pressure may push a particle in one substep.
+// Particles crammed into a corner can't be spread out however hard they're
+// pushed, so without a limit their pressure grows until the solve gives up,
+// and flings them across the box.
+const MAX_PRESSURE_SHIFT: f32 = 0.1;
+
+/// Predicts positions under the current pressures and measures the density there.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PcisphPrediction;
+
+/// Raises pressures where the predicted density is too high, and updates
+/// the accelerations to match.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PcisphCorrection;
+
+/// How the latest substep's pressure solve went, for the overlay.
+#[derive(Resource, Default)]
+pub struct PcisphStats {
+    pub iterations: u32,
+    // The largest predicted density error left, as a fraction of the target density.
+    pub density_error: f32,
+}
+
+/// Starts the substep from the current positions with no pressure.
+pub fn reset(
+    mut particles: Query<(
+        &ParticlePosition,
+        &mut PredictedParticlePosition,
+        &mut ParticlePressure,
+    )>,
+) {
+    for (ParticlePosition(x), mut next_x, mut pressure) in &mut particles {
+        next_x.0 = *x;
+        pressure.0 = 0.0;
+    }
+}
+
+/// Computes the accelerations due to everything but pressure, which stay
+/// the same while the pressures are solved for.
+#[allow(clippy::too_many_arguments)]
+pub fn update_non_pressure_accelerations(
+    mut accelerations: Query<(
+        Entity,
+        &mut ParticleNonPressureAcceleration,
+        &mut ParticleAcceleration,
+    )>,
+    particles: Query<(&PredictedParticlePosition, &ParticleVelocity, &ParticleDensity)>,
+    hash: Res<SpatialHash>,
+    force: Res<InteractionForce>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    // For each particle.
+    let update = |(
+        entity,
+        mut non_pressure,
+        mut acceleration,
+    ): (Entity, Mut<ParticleNonPressureAcceleration>, Mut<ParticleAcceleration>)| {
+        let (PredictedParticlePosition(pos_x), ParticleVelocity(vel_x), _) =
+            particles.get(entity).unwrap();
+
+        let mut viscosity_force = Vec2::ZERO;
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
+                continue;
+            }
+            let (_, ParticleVelocity(vel_i), ParticleDensity(density_i)) =
+                particles.get(neighbour.entity).unwrap();
+            let laplacian = kernels.viscosity.laplacian(displacement.length_squared());
+            viscosity_force += (vel_i - vel_x) * laplacian / density_i;
+        }
+
+        let mut acc = viscosity_force * config.viscosity;
+        if force.strength != 0.0 {
+            acc += physics::compute_interaction_acceleration(
+                pos_x, force.point, force.strength, config.interaction_radius,
+            );
+        }
+        acc.y -= config.gravity_force;
+
+        non_pressure.0 = acc;
+        acceleration.0 = acc;
+    };
+    for_each_particle(&mut accelerations, config.multithreaded, update);
+}
+
+/// Predicts where each particle would go under its current acceleration.
+pub fn predict_positions(
+    mut particles: Query<(
+        &ParticlePosition,
+        &mut PredictedParticlePosition,
+        &ParticleVelocity,
+        &ParticleAcceleration,
+    )>,
+    clock: Res<SimClock>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    let dt = clock.dt;
+    let predict = |(
+        ParticlePosition(x),
+        mut next_x,
+        ParticleVelocity(v),
+        ParticleAcceleration(a),
+    ): (
+        &ParticlePosition,
+        Mut<PredictedParticlePosition>,
+        &ParticleVelocity,
+        &ParticleAcceleration,
+    )| {
+        // Stop particles at the walls rather than bouncing them off, as a bounce
+        // turns a harder push into the wall into a harder push into the fluid.
+        let v = *v + *a * dt;
+        next_x.0 = physics::confine(*x + v * dt, &container.pose, &config);
+    };
+    for_each_particle(&mut particles, config.multithreaded, predict);
+}
+
+/// Predicts, then corrects pressures until the predicted density error is
+/// small enough or `pcisph_max_iterations` is reached.
+pub fn solve(world: &mut World) {
+    let config = world.resource::<SimConfig>();
+    let max_error = config.pcisph_max_density_error;
+    let max_iterations = config.pcisph_max_iterations;
+    let mut iterations = 0;
+    loop {
+        world.run_schedule(PcisphPrediction);
+        iterations += 1;
+        let error = world.resource::<PcisphStats>().density_error;
+        if error <= max_error || iterations == max_iterations {
+            break;
+        }
+        world.run_schedule(PcisphCorrection);
+    }
+    world.resource_mut::<PcisphStats>().iterations = iterations;
+}
+
+pub fn update_densities(
+    mut densities: Query<(Entity, &mut ParticleDensity)>,
+    positions: Query<&PredictedParticlePosition>,
+    mut stats: ResMut<PcisphStats>,
+    hash: Res<SpatialHash>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let kernel = &*kernels.density;
+    // For each particle.
+    let update = |(entity, mut density): (Entity, Mut<ParticleDensity>)| {
+        let PredictedParticlePosition(pos_x) = positions.get(entity).unwrap();
+        let mut sum = kernel.self_value();
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity != entity {
+                sum += kernel.value(displacement.length_squared());
+            }
+        }
+        density.0 = sum;
+    };
+    for_each_particle(&mut densities, config.multithreaded, update);
+
+    // Only compression counts, as the density always falls off at the surface.
+    let max_density = densities
+        .iter()
+        .map(|(_, ParticleDensity(density))| *density)
+        .fold(config.target_density, f32::max);
+    stats.density_error = max_density / config.target_density - 1.0;
+}
+
+pub fn update_pressures(
+    mut particles: Query<(&ParticleDensity, &mut ParticlePressure)>,
+    clock: Res<SimClock>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let scale = pressure_scale(&*kernels.density, config.target_density, clock.dt);
+    let update = |(ParticleDensity(density), mut pressure): (&ParticleDensity, Mut<ParticlePressure>)| {
+        // Pressure never pulls particles together.
+        pressure.0 = (pressure.0 + scale * (density - config.target_density)).max(0.0);
+    };
+    for_each_particle(&mut particles, config.multithreaded, update);
+}
+
+/// How much pressure it takes to undo a unit of density error within one
+/// substep, estimated for a particle with a full neighbourhood at rest.
+fn pressure_scale(kernel: &dyn Kernel, target_density: f32, dt: f32) -> f32 {
+    // A square grid with `target_density` particles per unit area.
+    let spacing = 1.0 / target_density.sqrt();
+    let reach = (kernel.support_radius() / spacing).ceil() as i32;
+    let mut gradient_sum = Vec2::ZERO;
+    let mut gradient_squared_sum = 0.0;
+    for i in -reach..=reach {
+        for j in -reach..=reach {
+            if i == 0 && j == 0 {
+                continue;
+            }
+            let displacement = spacing * Vec2::new(i as f32, j as f32);
+            let gradient = kernel.gradient(displacement, &|| Vec2::ZERO);
+            gradient_sum += gradient;
+            gradient_squared_sum += gradient.length_squared();
+        }
+    }
+    // `update_accelerations` pushes each pair apart with the average of their
+    // pressures rather than the sum, so pressure moves particles half as far
+    // as in Solenthaler and Pajarola's derivation.
+    let beta = (dt / target_density).powi(2);
+    1.0 / (beta * (gradient_sum.length_squared() + gradient_squared_sum))
+}
+
+/// Sets velocities from how far particles moved.
+pub fn update_velocities(
+    mut particles: Query<(&ParticlePosition, &PredictedParticlePosition, &mut ParticleVelocity)>,
+    clock: Res<SimClock>,
+    config: Res<SimConfig>,
+) {
+    let dt = clock.dt;
+    let update = |(
+        ParticlePosition(x),
+        PredictedParticlePosition(next_x),
+        mut v,
+    ): (&ParticlePosition, &PredictedParticlePosition, Mut<ParticleVelocity>)| {
+        v.0 = (*next_x - *x) / dt;
+    };
+    for_each_particle(&mut particles, config.multithreaded, update);
+}
+
+/// Adds the pressure force at the predicted positions to the non-pressure
+/// accelerations.
+#[allow(clippy::too_many_arguments)]
+pub fn update_accelerations(
+    mut accelerations: Query<(Entity, &mut ParticleAcceleration)>,
+    particles: Query<(
+        &PredictedParticlePosition,
+        &ParticlePressure,
+        &ParticleDensity,
+        &ParticleNonPressureAcceleration,
+    )>,
+    hash: Res<SpatialHash>,
+    clock: Res<SimClock>,
+    rng: Res<SimRng>,
+    container: Res<Container>,
+    kernels: Res<Kernels>,
+    config: Res<SimConfig>,
+) {
+    let dt = clock.dt;
+    let max_acceleration = MAX_PRESSURE_SHIFT * kernels.density.support_radius() / (dt * dt);
+    // For each particle.
+    let update = |(entity, mut acceleration): (Entity, Mut<ParticleAcceleration>)| {
+        let (
+            PredictedParticlePosition(pos_x),
+            ParticlePressure(pressure_x),
+            ParticleDensity(density_x),
+            ParticleNonPressureAcceleration(non_pressure),
+        ) = particles.get(entity).unwrap();
+
+        let mut pressure_gradient = Vec2::ZERO;
+        let neighbours = hash.periodic_neighbours(*pos_x, &container.pose, config.periodic);
+        for (displacement, neighbour) in neighbours {
+            if neighbour.entity == entity {
+                continue;
+            }
+            let (_, ParticlePressure(pressure_i), ParticleDensity(density_i), _) =
+                particles.get(neighbour.entity).unwrap();
+            let fallback_dir = rng.pair_fallback_dir(clock.step, entity, neighbour.entity);
+            let shared_pressure = 0.5 * (pressure_x + pressure_i);
+            pressure_gradient +=
+                shared_pressure * kernels.density.gradient(displacement, &fallback_dir) / density_i;
+        }
+
+        let pressure_acceleration = (pressure_gradient / density_x).clamp_length_max(max_acceleration);
+        acceleration.0 = *non_pressure + pressure_acceleration;
+    };
+    for_each_particle(&mut accelerations, config.multithreaded, update);
+}
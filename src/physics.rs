@@ -1,97 +1,241 @@
+use std::fmt;
+
 use bevy::prelude::*;
 use glam::f32::Vec2;
+use serde::{Deserialize, Serialize};
 
-use crate::consts::{
-    COLLISION_DAMPING,
-    DENSITY_KERNEL,
-    PARTICLE_SCREEN_RADIUS,
-    STARTUP_DAMPING_INTERVAL,
-    TARGET_DENSITY,
-};
-use crate::consts_private::{DENSITY_FACTOR, SCREEN_FACTOR};
+use crate::config::SimConfig;
+use crate::container::{Container, ContainerPose};
+use crate::kernel::{Kernel, Kernels};
 use crate::maths::{lerp, smooth_ramp};
-use crate::particle::PHYSICAL_HALF_SIZE;
+use crate::obstacle::Obstacle;
+use crate::simulation::SimClock;
 
 #[derive(Resource)]
 pub struct StartupDamping(pub f32);
 
-const INV_DAMPING_INTERVAL: f32 = 1.0 / STARTUP_DAMPING_INTERVAL;
-
 pub fn update_startup_damping(
-    time: Res<Time>,
+    clock: Res<SimClock>,
+    config: Res<SimConfig>,
     mut damping: ResMut<StartupDamping>,
 ) {
-    damping.0 = smooth_ramp(time.elapsed_secs() * INV_DAMPING_INTERVAL);
+    damping.0 = smooth_ramp(clock.elapsed / config.startup_damping_interval);
 }
 
-pub fn density_to_pressure(density: f32, pressure_multiplier: f32) -> f32 {
-    let density_error = density - TARGET_DENSITY;
-    density_error * pressure_multiplier
+/// How pressure follows from density, as named in config files.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EquationOfState {
+    // `pressure_multiplier` times the density error, which pulls particles
+    // together below the target density.
+    Linear,
+    // Like `Linear`, but never negative, so the fluid isn't sticky.
+    Clamped,
+    // Tait's weakly compressible `B((ρ/ρ0)^γ - 1)`, with `B = ρ0 c² / γ`
+    // for speed of sound `c` and exponent `γ`.
+    Tait,
+}
+
+/// The pressure at `density` under the configured equation of state,
+/// scaled by `damping`.
+pub fn density_to_pressure(density: f32, damping: f32, config: &SimConfig) -> f32 {
+    let target_density = config.target_density;
+    let pressure_multiplier = damping * config.pressure_multiplier;
+    match config.equation_of_state {
+        EquationOfState::Linear => (density - target_density) * pressure_multiplier,
+        EquationOfState::Clamped => (density - target_density).max(0.0) * pressure_multiplier,
+        EquationOfState::Tait => {
+            let exponent = config.tait_exponent;
+            let stiffness = target_density * config.speed_of_sound * config.speed_of_sound / exponent;
+            damping * stiffness * ((density / target_density).powf(exponent) - 1.0)
+        }
+    }
 }
 
-const EDGE_DENSITY: f32 = 1.2 * TARGET_DENSITY;
-const EDGE_DENSITY_FACTOR: f32 = EDGE_DENSITY / DENSITY_FACTOR;
+fn edge_density(config: &SimConfig) -> f32 {
+    1.2 * config.target_density
+}
 
-pub fn compute_edge_density(sample_point: &Vec2) -> f32 {
+pub fn compute_edge_density(
+    sample_point: &Vec2,
+    container: &ContainerPose,
+    kernel: &dyn Kernel,
+    config: &SimConfig,
+) -> f32 {
+    let half_size = container.half_size;
+    let local_point = container.to_local(*sample_point);
     let edge_displacement = (
-        PHYSICAL_HALF_SIZE.0 - sample_point.x.abs(),
-        PHYSICAL_HALF_SIZE.1 - sample_point.y.abs(),
+        half_size.x - local_point.x.abs(),
+        half_size.y - local_point.y.abs(),
     );
     let displacement_squared = (
         edge_displacement.0 * edge_displacement.0,
         edge_displacement.1 * edge_displacement.1,
     );
-    let edge_density =
-        DENSITY_KERNEL.influence(displacement_squared.0) +
-        DENSITY_KERNEL.influence(displacement_squared.1);
-    edge_density * EDGE_DENSITY_FACTOR
+    // Periodic sides are open, so they don't repel.
+    let influence = |periodic, displacement_squared| if periodic {
+        0.0
+    } else {
+        kernel.value(displacement_squared)
+    };
+    let mut edge_density =
+        influence(config.periodic.0, displacement_squared.0) +
+        influence(config.periodic.1, displacement_squared.1);
+    // Obstacle surfaces repel like the edges of the box.
+    for obstacle in &config.obstacles {
+        let distance = obstacle.sdf(*sample_point).max(0.0);
+        edge_density += kernel.value(distance * distance);
+    }
+    edge_density * edge_density_factor(kernel, config)
 }
 
+fn edge_density_factor(kernel: &dyn Kernel, config: &SimConfig) -> f32 {
+    edge_density(config) / kernel.self_value()
+}
+
+/// `fallback_dir` is called with the axis (0 for x, 1 for y) of any edge
+/// the sample point lies exactly on, or 2 plus the index of any obstacle,
+/// see `Kernel::gradient`.
 pub fn compute_edge_acceleration(
-    sample_point: &Vec2, sample_density: f32, pressure_multiplier: f32,
+    sample_point: &Vec2,
+    sample_density: f32,
+    damping: f32,
+    container: &ContainerPose,
+    kernel: &dyn Kernel,
+    config: &SimConfig,
+    fallback_dir: impl Fn(u64) -> Vec2,
 ) -> Vec2 {
+    let half_size = container.half_size;
+    let local_point = container.to_local(*sample_point);
     let edge_displacement = (
-        sample_point.x - if sample_point.x > 0.0 {
-            PHYSICAL_HALF_SIZE.0
+        local_point.x - if local_point.x > 0.0 {
+            half_size.x
         } else {
-            -PHYSICAL_HALF_SIZE.0
+            -half_size.x
         },
-        sample_point.y - if sample_point.y > 0.0 {
-            PHYSICAL_HALF_SIZE.1
+        local_point.y - if local_point.y > 0.0 {
+            half_size.y
         } else {
-            -PHYSICAL_HALF_SIZE.1
+            -half_size.y
         },
     );
-    let edge_pressure = density_to_pressure(EDGE_DENSITY, pressure_multiplier);
-    let acc = edge_pressure * (
-        DENSITY_KERNEL.gradient(Vec2 {x: edge_displacement.0, y: 0.0}) +
-        DENSITY_KERNEL.gradient(Vec2 {x: 0.0, y: edge_displacement.1})
-    ) / EDGE_DENSITY;
+    let edge_density = edge_density(config);
+    let edge_pressure = density_to_pressure(edge_density, damping, config);
+    let gradient = |periodic, displacement, axis| if periodic {
+        Vec2::ZERO
+    } else {
+        kernel.gradient(displacement, &|| fallback_dir(axis))
+    };
+    // The box edges are axis aligned in container space.
+    let mut gradient = container.rotate(
+        gradient(config.periodic.0, Vec2 {x: edge_displacement.0, y: 0.0}, 0) +
+        gradient(config.periodic.1, Vec2 {x: 0.0, y: edge_displacement.1}, 1)
+    );
+    for (i, obstacle) in config.obstacles.iter().enumerate() {
+        // The displacement from the nearest point on the surface.
+        let distance = obstacle.sdf(*sample_point).max(0.0);
+        gradient += kernel.gradient(
+            distance * obstacle.normal(*sample_point), &|| fallback_dir(2 + i as u64),
+        );
+    }
+    let acc = edge_pressure * gradient / edge_density;
     acc / sample_density
 }
 
+/// Pulls particles within `radius` of `point` towards it, or pushes them away
+/// if `strength` is negative, fading out linearly towards the edge.
+pub fn compute_interaction_acceleration(
+    sample_point: &Vec2,
+    point: Vec2,
+    strength: f32,
+    radius: f32,
+) -> Vec2 {
+    let offset = point - sample_point;
+    let distance = offset.length();
+    if distance >= radius || distance < f32::EPSILON {
+        return Vec2::ZERO;
+    }
+    let falloff = 1.0 - distance / radius;
+    offset / distance * strength * falloff
+}
+
+// Safety factors for the adaptive timestep criteria.
+const FORCE_FACTOR: f32 = 0.25;
+const VISCOSITY_FACTOR: f32 = 0.125;
+
+/// Which criterion limited the length of a substep.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimestepLimit {
+    // The configured substep length.
+    #[default]
+    Substep,
+    // The CFL condition on particle speed.
+    Velocity,
+    // The force condition on particle acceleration.
+    Force,
+    // The viscous diffusion condition.
+    Viscosity,
+}
+
+impl fmt::Display for TimestepLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TimestepLimit::Substep => "substep",
+            TimestepLimit::Velocity => "CFL",
+            TimestepLimit::Force => "force",
+            TimestepLimit::Viscosity => "viscosity",
+        })
+    }
+}
+
+/// Computes the largest timestep, up to `max_dt`, which keeps the simulation
+/// stable given the fastest particle speed and largest acceleration.
+/// Lengths are measured in the largest support radius of the `kernels`.
+pub fn stable_timestep(
+    max_speed: f32, max_acceleration: f32, max_dt: f32, config: &SimConfig, kernels: &Kernels,
+) -> (f32, TimestepLimit) {
+    let h = kernels.support_radius();
+    // Zero speeds, accelerations or viscosity give infinite limits, which never apply.
+    let criteria = [
+        (config.cfl_number * h / max_speed, TimestepLimit::Velocity),
+        (FORCE_FACTOR * (h / max_acceleration).sqrt(), TimestepLimit::Force),
+        (VISCOSITY_FACTOR * h * h / config.viscosity, TimestepLimit::Viscosity),
+    ];
+    let mut dt = max_dt;
+    let mut limit = TimestepLimit::Substep;
+    for (criterion_dt, criterion) in criteria {
+        if criterion_dt < dt {
+            dt = criterion_dt;
+            limit = criterion;
+        }
+    }
+    (dt, limit)
+}
+
 pub struct VerletResult {
     pub prev_x: Vec2,
     pub x: Vec2,
     pub v: Vec2,
     pub moved: bool,
+    // How far the particle was moved by wrapping around periodic sides.
+    pub wrap: Vec2,
 }
 
-pub const PARTICLE_RADIUS: f32 = PARTICLE_SCREEN_RADIUS / SCREEN_FACTOR;
-const PARTICLE_CENTRE_BOUND: (f32, f32) = (
-    PHYSICAL_HALF_SIZE.0 - PARTICLE_RADIUS, PHYSICAL_HALF_SIZE.1 - PARTICLE_RADIUS,
-);
-
 /// Computes the verlet integrated next position and velocity of a particle
 /// based on its acceleration and previous and current positions.
+/// `prev_dt` is how long the particle took to move from `prev_x` to `x`,
+/// which differs from `dt` between adaptive substeps.
 /// In the event of a boundary collision, adjusts the previous position,
 /// new position, and velocity.
+#[allow(clippy::too_many_arguments)]
 pub fn verlet(
     prev_x: &Option<Vec2>,
     x: &Vec2,
     v: &Vec2,
     a: &Vec2,
     dt: f32,
+    prev_dt: f32,
+    container: &Container,
+    config: &SimConfig,
 ) -> VerletResult {
     let delta_x = match prev_x {
         None => {
@@ -99,15 +243,19 @@ pub fn verlet(
             *v * dt + 0.5 * a * dt * dt
         },
         Some(prev_x) => {
-            *x - prev_x + a * dt * dt
+            // Time corrected verlet, which reduces to the usual
+            // `x - prev_x + a dt²` when the timestep doesn't change.
+            (*x - prev_x) * (dt / prev_dt) + a * dt * (dt + prev_dt) * 0.5
         },
     };
-    if delta_x.length() < std::f32::EPSILON {
+    // Particles at rest can still be hit by a moving wall.
+    if delta_x.length() < std::f32::EPSILON && !container.is_moving() {
         return VerletResult {
             prev_x: *x,
             x: *x,
             v: Vec2::ZERO,
             moved: false,
+            wrap: Vec2::ZERO,
         }
     }
 
@@ -115,47 +263,260 @@ pub fn verlet(
     let mut next_x = *x + delta_x;
     let mut next_v = delta_x / dt;
 
-    boundary_check(
-        &mut curr_x.x,
-        &mut next_x.x,
-        &mut next_v.x,
-        -PARTICLE_CENTRE_BOUND.0, PARTICLE_CENTRE_BOUND.0,
-    );
-    boundary_check(
-        &mut curr_x.y,
-        &mut next_x.y,
-        &mut next_v.y,
-        -PARTICLE_CENTRE_BOUND.1, PARTICLE_CENTRE_BOUND.1,
-    );
+    let wrap = container_check(&mut curr_x, &mut next_x, &mut next_v, dt, container, config);
+
+    let radius = config.particle_radius();
+    for obstacle in &config.obstacles {
+        obstacle_check(
+            &mut curr_x,
+            &mut next_x,
+            &mut next_v,
+            obstacle,
+            radius,
+            config.collision_damping,
+        );
+    }
 
     VerletResult {
         prev_x: curr_x,
         x: next_x,
         v: next_v,
         moved: true,
+        wrap,
+    }
+}
+
+/// Bounces a particle off the container walls, working in container space
+/// where the walls are axis aligned and still, so that moving walls pass on
+/// their velocity. Particles leaving through periodic sides are moved to the
+/// opposite side instead, and the offset they were moved by is returned.
+fn container_check(
+    prev_x: &mut Vec2,
+    new_x: &mut Vec2,
+    v: &mut Vec2,
+    dt: f32,
+    container: &Container,
+    config: &SimConfig,
+) -> Vec2 {
+    let pose = &container.pose;
+    let mut prev_local = container.prev_to_local(*prev_x);
+    let mut new_local = pose.to_local(*new_x);
+    let mut v_local = Vec2::from_angle(-pose.angle)
+        .rotate(*v - container.velocity_at(new_local, dt));
+
+    let bound = pose.half_size - config.particle_radius();
+    let unwrapped = new_local;
+    let hit_x = if config.periodic.0 {
+        periodic_check(&mut prev_local.x, &mut new_local.x, pose.half_size.x)
+    } else {
+        boundary_check(
+            &mut prev_local.x,
+            &mut new_local.x,
+            &mut v_local.x,
+            -bound.x, bound.x,
+            config.collision_damping,
+        )
+    };
+    let hit_y = if config.periodic.1 {
+        periodic_check(&mut prev_local.y, &mut new_local.y, pose.half_size.y)
+    } else {
+        boundary_check(
+            &mut prev_local.y,
+            &mut new_local.y,
+            &mut v_local.y,
+            -bound.y, bound.y,
+            config.collision_damping,
+        )
+    };
+    if hit_x || hit_y {
+        *prev_x = container.local_to_prev(prev_local);
+        *new_x = pose.to_world(new_local);
+        *v = pose.rotate(v_local) + container.velocity_at(new_local, dt);
+    }
+    // Only the periodic sides move particles by whole box widths.
+    let wrap = Vec2::new(
+        if config.periodic.0 { new_local.x - unwrapped.x } else { 0.0 },
+        if config.periodic.1 { new_local.y - unwrapped.y } else { 0.0 },
+    );
+    pose.rotate(wrap)
+}
+
+/// Moves a particle which left through one periodic side to the opposite one,
+/// keeping its previous position the same distance behind.
+/// Returns whether the particle wrapped around.
+fn periodic_check(prev_x: &mut f32, new_x: &mut f32, half_size: f32) -> bool {
+    let shift = periodic_shift(*new_x, half_size);
+    if shift == 0.0 {
+        return false;
     }
+    *prev_x += shift;
+    *new_x += shift;
+    true
 }
 
+/// How far to move a coordinate which left through one periodic side to
+/// bring it back in through the opposite one.
+fn periodic_shift(x: f32, half_size: f32) -> f32 {
+    if x < -half_size {
+        2.0 * half_size
+    } else if x > half_size {
+        -2.0 * half_size
+    } else {
+        0.0
+    }
+}
+
+/// Moves a position back inside the solid walls of the container and out of
+/// any obstacles, without bouncing.
+pub fn confine(x: Vec2, container: &ContainerPose, config: &SimConfig) -> Vec2 {
+    let radius = config.particle_radius();
+    let bound = container.half_size - radius;
+    let mut local = container.to_local(x);
+    if !config.periodic.0 {
+        local.x = local.x.clamp(-bound.x, bound.x);
+    }
+    if !config.periodic.1 {
+        local.y = local.y.clamp(-bound.y, bound.y);
+    }
+    let mut x = container.to_world(local);
+    for obstacle in &config.obstacles {
+        let distance = obstacle.sdf(x);
+        if distance < radius {
+            x += (radius - distance) * obstacle.normal(x);
+        }
+    }
+    x
+}
+
+/// Moves a position which left through a periodic side of the container in
+/// through the opposite one, returning the offset it was moved by.
+pub fn wrap_periodic(x: &mut Vec2, container: &ContainerPose, config: &SimConfig) -> Vec2 {
+    let local = container.to_local(*x);
+    let shift = Vec2::new(
+        if config.periodic.0 { periodic_shift(local.x, container.half_size.x) } else { 0.0 },
+        if config.periodic.1 { periodic_shift(local.y, container.half_size.y) } else { 0.0 },
+    );
+    let wrap = container.rotate(shift);
+    *x += wrap;
+    wrap
+}
+
+/// Returns whether the particle hit a wall.
 fn boundary_check(
     prev_x: &mut f32,
     new_x: &mut f32,
     v: &mut f32,
     low: f32,
     high: f32,
-) {
+    collision_damping: f32,
+) -> bool {
     if *new_x < low {
         // Reflect both positions.
         *prev_x = 2.0 * low - *prev_x;
         *new_x = 2.0 * low - *new_x;
         // Attenuate velocity and adjust prev_x.
-        *prev_x = lerp(*new_x, *prev_x, COLLISION_DAMPING);
-        *v *= -COLLISION_DAMPING;
+        *prev_x = lerp(*new_x, *prev_x, collision_damping);
+        *v *= -collision_damping;
+        true
     } else if *new_x > high {
         // Reflect both positions.
         *prev_x = 2.0 * high - *prev_x;
         *new_x = 2.0 * high - *new_x;
         // Attenuate velocity and adjust prev_x.
-        *prev_x = lerp(*new_x, *prev_x, COLLISION_DAMPING);
-        *v *= -COLLISION_DAMPING;
+        *prev_x = lerp(*new_x, *prev_x, collision_damping);
+        *v *= -collision_damping;
+        true
+    } else {
+        false
+    }
+}
+
+/// The same reflect-and-damp response as `boundary_check`,
+/// with the wall being the obstacle's surface nearest the particle.
+fn obstacle_check(
+    prev_x: &mut Vec2,
+    new_x: &mut Vec2,
+    v: &mut Vec2,
+    obstacle: &Obstacle,
+    radius: f32,
+    collision_damping: f32,
+) {
+    let penetration = radius - obstacle.sdf(*new_x);
+    if penetration <= 0.0 {
+        return;
+    }
+    let normal = obstacle.normal(*new_x);
+    // Reflect both positions in the surface, offset by the particle radius.
+    let wall = *new_x + penetration * normal;
+    let reflect = |x: Vec2| x - 2.0 * (x - wall).dot(normal) * normal;
+    *prev_x = reflect(*prev_x);
+    *new_x = reflect(*new_x);
+    // Attenuate velocity and adjust prev_x.
+    *prev_x = new_x.lerp(*prev_x, collision_damping);
+    let normal_speed = v.dot(normal);
+    if normal_speed < 0.0 {
+        *v -= (1.0 + collision_damping) * normal_speed * normal;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verlet_follows_parabola_through_uneven_steps() {
+        let config = SimConfig::default();
+        let container = Container::initial(&config, &Kernels::new(&config));
+        let a = Vec2::new(0.5, -1.0);
+        let start = Vec2::new(-3.0, 2.0);
+        let v0 = Vec2::new(1.0, 0.5);
+        let exact = |t: f32| start + v0 * t + 0.5 * a * t * t;
+
+        let mut prev_x = None;
+        let mut x = start;
+        let mut v = v0;
+        let mut t = 0.0;
+        let mut prev_dt = 0.0;
+        for dt in [0.01, 0.004, 0.013, 0.0005, 0.02, 0.007] {
+            let res = verlet(&prev_x, &x, &v, &a, dt, prev_dt, &container, &config);
+            (prev_x, x, v) = (Some(res.prev_x), res.x, res.v);
+            t += dt;
+            prev_dt = dt;
+            assert!((x - exact(t)).length() < 1e-5, "{} != {} at t = {}", x, exact(t), t);
+        }
+    }
+
+    #[test]
+    fn clamped_pressure_is_never_negative() {
+        let config = SimConfig { equation_of_state: EquationOfState::Clamped, ..Default::default() };
+        let linear = SimConfig { equation_of_state: EquationOfState::Linear, ..config.clone() };
+        let target = config.target_density;
+        assert_eq!(density_to_pressure(0.5 * target, 1.0, &config), 0.0);
+        assert_eq!(density_to_pressure(target, 1.0, &config), 0.0);
+        for (density, damping) in [(1.2 * target, 1.0), (1.5 * target, 0.3)] {
+            assert_eq!(
+                density_to_pressure(density, damping, &config),
+                density_to_pressure(density, damping, &linear),
+            );
+        }
+    }
+
+    #[test]
+    fn tait_pressure_stiffens_with_compression() {
+        let config = SimConfig { equation_of_state: EquationOfState::Tait, ..Default::default() };
+        let target = config.target_density;
+        let pressure = |density| density_to_pressure(density, 1.0, &config);
+        assert_eq!(pressure(target), 0.0);
+        assert!(pressure(0.9 * target) < 0.0);
+        // The slope at the target density is the speed of sound squared.
+        let h = 1e-3 * target;
+        let slope = (pressure(target + h) - pressure(target - h)) / (2.0 * h);
+        let c2 = config.speed_of_sound * config.speed_of_sound;
+        assert!((slope - c2).abs() < 1e-2 * c2, "slope {} != {}", slope, c2);
+        // Twice the target density gives B (2^γ - 1).
+        let stiffness = target * c2 / config.tait_exponent;
+        let expected = stiffness * (2.0_f32.powf(config.tait_exponent) - 1.0);
+        assert!((pressure(2.0 * target) - expected).abs() < 1e-3 * expected);
+        assert_eq!(density_to_pressure(2.0 * target, 0.5, &config), 0.5 * pressure(2.0 * target));
     }
 }
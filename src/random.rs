@@ -1,16 +1,69 @@
+use bevy::prelude::{Entity, Resource};
 use glam::f32::Vec2;
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
-pub fn point_in_box(extent: (f32, f32)) -> (f32, f32) {
-    let mut rng = rand::thread_rng();
+/// The single source of randomness for the simulation, so that runs with the
+/// same seed and config are reproducible.
+/// Serializes with the generator's position in its stream, so saved states
+/// continue with the same numbers.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct SimRng {
+    seed: u64,
+    // The generator behind `StdRng`, which unlike it can be serialized.
+    rng: ChaCha12Rng,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, rng: ChaCha12Rng::seed_from_u64(seed) }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rng(&mut self) -> &mut ChaCha12Rng {
+        &mut self.rng
+    }
+
+    /// A random vector within the unit disk which only depends on the seed and
+    /// the given keys. Unlike `rng`, this is safe to use from parallel passes
+    /// without the result depending on the order work is done in.
+    pub fn keyed_vec_within_disk(&self, keys: [u64; 3]) -> Vec2 {
+        let key = keys.into_iter().fold(self.seed, |hash, key| mix(hash ^ key));
+        vec_within_disk(&mut StdRng::seed_from_u64(key), 1.0)
+    }
+
+    /// The direction to push apart `entity` and `neighbour` if they are on
+    /// top of each other at `step`, for `Kernel::gradient`.
+    pub fn pair_fallback_dir(
+        &self,
+        step: u64,
+        entity: Entity,
+        neighbour: Entity,
+    ) -> impl Fn() -> Vec2 + '_ {
+        move || self.keyed_vec_within_disk([step, entity.to_bits(), neighbour.to_bits()])
+    }
+}
+
+// The SplitMix64 finalizer.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+pub fn point_in_box(rng: &mut impl Rng, extent: (f32, f32)) -> (f32, f32) {
     (
         rng.gen_range(-extent.0..extent.0),
         rng.gen_range(-extent.1..extent.1),
     )
 }
 
-pub fn vec_within_disk(max_radius: f32) -> Vec2 {
-    let mut rng = rand::thread_rng();
+pub fn vec_within_disk(rng: &mut impl Rng, max_radius: f32) -> Vec2 {
     let v_mag: f32 = rng.gen_range(0.0..max_radius);
     let z: f32 = rng.gen_range(0.0..std::f32::consts::TAU);
     Vec2{
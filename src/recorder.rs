@@ -0,0 +1,236 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use crate::config::SimConfig;
+use crate::container::Container;
+use crate::particle::{
+    ParticleAcceleration,
+    ParticleDensity,
+    ParticlePosition,
+    ParticlePressure,
+    ParticleVelocity,
+};
+use crate::simulation::{self, SimClock, SimulationSet};
+
+/// The header of every CSV file of particle states. `id` is the particle's
+/// entity, which stays the same from frame to frame.
+pub const CSV_HEADER: &str = "id,x,y,vx,vy,ax,ay,density,pressure";
+/// Lists each recorded frame with the step and time it was taken at,
+/// and where the container was.
+pub const INDEX_FILE: &str = "index.csv";
+/// The header of the index file.
+pub const INDEX_HEADER: &str = "frame,step,time,centre_x,centre_y,angle,half_width,half_height";
+/// The config the recorded run used, so it can be replayed as it was.
+/// Written as RON, like snapshots, as TOML can't hold every seed.
+pub const CONFIG_FILE: &str = "config.ron";
+
+pub type ParticleRecord<'a> = (
+    Entity,
+    &'a ParticlePosition,
+    &'a ParticleVelocity,
+    &'a ParticleAcceleration,
+    &'a ParticleDensity,
+    &'a ParticlePressure,
+);
+
+/// Writes the particle state every `stride` fixed steps to `dir`,
+/// as both CSV and legacy VTK polydata.
+pub struct RecorderPlugin {
+    pub dir: PathBuf,
+    pub stride: u32,
+}
+
+impl Plugin for RecorderPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Recorder {
+                dir: self.dir.clone(),
+                stride: self.stride.max(1),
+                steps: 0,
+                frames: 0,
+                failed: false,
+            })
+            .add_systems(Startup, start)
+            .add_systems(FixedUpdate, record.after(SimulationSet).run_if(simulation::running));
+    }
+}
+
+#[derive(Resource)]
+pub struct Recorder {
+    dir: PathBuf,
+    stride: u32,
+    // Fixed steps taken since recording started.
+    steps: u32,
+    frames: u32,
+    // Stop after the first error rather than report it every step.
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn frame_path(dir: &Path, frame: u32, extension: &str) -> PathBuf {
+        dir.join(format!("frame_{:06}.{}", frame, extension))
+    }
+
+    fn fail(&mut self, err: io::Error) {
+        error!("stopped recording to {}: {}", self.dir.display(), err);
+        self.failed = true;
+    }
+}
+
+fn start(mut recorder: ResMut<Recorder>, config: Res<SimConfig>) {
+    let dir = recorder.dir.clone();
+    let result = fs::create_dir_all(&dir).and_then(|_| {
+        let config = ron::ser::to_string_pretty(&*config, ron::ser::PrettyConfig::default())
+            .map_err(io::Error::other)?;
+        fs::write(dir.join(CONFIG_FILE), config)?;
+        let mut index = File::create(dir.join(INDEX_FILE))?;
+        writeln!(index, "{}", INDEX_HEADER)
+    });
+    match result {
+        Ok(()) => info!("recording to {}", dir.display()),
+        Err(err) => recorder.fail(err),
+    }
+}
+
+fn record(
+    mut recorder: ResMut<Recorder>,
+    particles: Query<ParticleRecord>,
+    clock: Res<SimClock>,
+    container: Res<Container>,
+) {
+    if recorder.failed {
+        return;
+    }
+    recorder.steps += 1;
+    if !recorder.steps.is_multiple_of(recorder.stride) {
+        return;
+    }
+
+    let frame = recorder.frames;
+    let dir = recorder.dir.clone();
+    let particles: Vec<_> = particles.iter().collect();
+    let result = write_csv(&Recorder::frame_path(&dir, frame, "csv"), particles.iter().copied())
+        .and_then(|_| write_vtk(
+            &Recorder::frame_path(&dir, frame, "vtk"),
+            &particles,
+            clock.elapsed,
+        ))
+        .and_then(|_| {
+            let mut index = OpenOptions::new().append(true).open(dir.join(INDEX_FILE))?;
+            let pose = &container.pose;
+            writeln!(
+                index,
+                "{},{},{},{},{},{},{},{}",
+                frame, clock.step, clock.elapsed,
+                pose.centre.x, pose.centre.y, pose.angle, pose.half_size.x, pose.half_size.y,
+            )
+        });
+    match result {
+        Ok(()) => recorder.frames += 1,
+        Err(err) => recorder.fail(err),
+    }
+}
+
+/// Writes one CSV row per particle with its full physical state.
+pub fn write_csv<'a>(
+    path: &Path,
+    particles: impl Iterator<Item = ParticleRecord<'a>>,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "{}", CSV_HEADER)?;
+    for (
+        entity,
+        ParticlePosition(x),
+        ParticleVelocity(v),
+        ParticleAcceleration(a),
+        ParticleDensity(density),
+        ParticlePressure(pressure),
+    ) in particles {
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{}",
+            entity.to_bits(), x.x, x.y, v.x, v.y, a.x, a.y, density, pressure,
+        )?;
+    }
+    file.flush()
+}
+
+/// Writes the particles as legacy VTK polydata, one vertex per particle,
+/// with the rest of their state as point data.
+pub fn write_vtk(path: &Path, particles: &[ParticleRecord], time: f32) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let count = particles.len();
+    writeln!(file, "# vtk DataFile Version 3.0")?;
+    writeln!(file, "flow particles at t = {}", time)?;
+    writeln!(file, "ASCII")?;
+    writeln!(file, "DATASET POLYDATA")?;
+
+    writeln!(file, "POINTS {} float", count)?;
+    for (_, ParticlePosition(x), ..) in particles {
+        writeln!(file, "{} {} 0", x.x, x.y)?;
+    }
+    writeln!(file, "VERTICES {} {}", count, 2 * count)?;
+    for i in 0..count {
+        writeln!(file, "1 {}", i)?;
+    }
+
+    writeln!(file, "POINT_DATA {}", count)?;
+    writeln!(file, "VECTORS velocity float")?;
+    for (_, _, ParticleVelocity(v), ..) in particles {
+        writeln!(file, "{} {} 0", v.x, v.y)?;
+    }
+    writeln!(file, "VECTORS acceleration float")?;
+    for (_, _, _, ParticleAcceleration(a), ..) in particles {
+        writeln!(file, "{} {} 0", a.x, a.y)?;
+    }
+    writeln!(file, "SCALARS density float 1")?;
+    writeln!(file, "LOOKUP_TABLE default")?;
+    for (.., ParticleDensity(density), _) in particles {
+        writeln!(file, "{}", density)?;
+    }
+    writeln!(file, "SCALARS pressure float 1")?;
+    writeln!(file, "LOOKUP_TABLE default")?;
+    for (.., ParticlePressure(pressure)) in particles {
+        writeln!(file, "{}", pressure)?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::Replay;
+    use crate::simulation::Solver;
+    use crate::test_utils::{app, run_until, test_config};
+
+    #[test]
+    fn recorded_frames_load_back() {
+        let dir = std::env::temp_dir().join(format!("flow_recording_{}", std::process::id()));
+        let config = SimConfig { substeps: 1, adaptive_timestep: false, ..test_config(Solver::Sph) };
+        let mut app = app(config);
+        app.add_plugins(RecorderPlugin { dir: dir.clone(), stride: 5 });
+        run_until(&mut app, 20);
+        let replay = Replay::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let replay = replay.unwrap();
+
+        let steps: Vec<u64> = replay.frames.iter().map(|frame| frame.step).collect();
+        assert_eq!(steps, [5, 10, 15, 20]);
+        let world = app.world_mut();
+        let last = &replay.frames[3];
+        assert_eq!(last.time, world.resource::<SimClock>().elapsed);
+        let particles: Vec<_> = world
+            .query::<(Entity, &ParticlePosition, &ParticleVelocity)>()
+            .iter(world)
+            .map(|(entity, ParticlePosition(x), ParticleVelocity(v))| (entity.to_bits(), *x, *v))
+            .collect();
+        assert_eq!(last.particles.len(), particles.len());
+        for (id, x, v) in particles {
+            let recorded = last.particle(id).unwrap();
+            assert_eq!((recorded.position, recorded.velocity), (x, v));
+        }
+    }
+}
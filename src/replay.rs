@@ -0,0 +1,410 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::config::{ConfigError, SimConfig};
+use crate::container::{Container, ContainerPose};
+use crate::kernel::Kernels;
+use crate::particle::{
+    ParticleAcceleration,
+    ParticleDensity,
+    ParticlePosition,
+    ParticlePressure,
+    ParticleVelocity,
+    StepStartPosition,
+};
+use crate::recorder::{self, Recorder};
+
+// Playback speed limits, as multiples of real time.
+const MIN_SPEED: f32 = 1.0 / 16.0;
+const MAX_SPEED: f32 = 64.0;
+
+#[derive(Clone, Copy)]
+pub struct RecordedParticle {
+    // The same particle has the same id in every frame.
+    pub id: u64,
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub acceleration: Vec2,
+    pub density: f32,
+    pub pressure: f32,
+}
+
+pub struct Frame {
+    pub step: u64,
+    pub time: f32,
+    pub container: ContainerPose,
+    // Sorted by id.
+    pub particles: Vec<RecordedParticle>,
+}
+
+impl Frame {
+    pub fn particle(&self, id: u64) -> Option<&RecordedParticle> {
+        self.particles
+            .binary_search_by_key(&id, |particle| particle.id)
+            .ok()
+            .map(|i| &self.particles[i])
+    }
+}
+
+/// A run written by the recorder, loaded fully into memory.
+#[derive(Resource, Clone)]
+pub struct Replay {
+    pub config: SimConfig,
+    pub frames: Arc<[Frame]>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(PathBuf, std::io::Error),
+    Parse { path: PathBuf, line: usize },
+    ParseConfig(PathBuf, ron::error::SpannedError),
+    Config(ConfigError),
+    Empty(PathBuf),
+    // Frame times must increase, which they don't if the recorded run was
+    // rewound or loaded from a snapshot.
+    Unordered { path: PathBuf, frame: u32 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(path, err) =>
+                write!(f, "could not read {}: {}", path.display(), err),
+            ReplayError::Parse { path, line } =>
+                write!(f, "could not parse {} at line {}", path.display(), line),
+            ReplayError::ParseConfig(path, err) =>
+                write!(f, "could not parse {}: {}", path.display(), err),
+            ReplayError::Config(err) => write!(f, "{}", err),
+            ReplayError::Empty(path) =>
+                write!(f, "no frames were recorded in {}", path.display()),
+            ReplayError::Unordered { path, frame } =>
+                write!(f, "frame {} in {} is not later than the one before", frame, path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Reads every line after the header, parsing its comma separated values
+/// with `parse`, which returns `None` if they are malformed.
+fn read_rows<T>(
+    path: &Path,
+    parse: impl Fn(&[&str]) -> Option<T>,
+) -> Result<Vec<T>, ReplayError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| ReplayError::Io(path.to_path_buf(), err))?;
+    text.lines()
+        .enumerate()
+        .skip(1)
+        .map(|(i, line)| {
+            let values: Vec<&str> = line.split(',').map(str::trim).collect();
+            parse(&values).ok_or(ReplayError::Parse { path: path.to_path_buf(), line: i + 1 })
+        })
+        .collect()
+}
+
+fn parse_floats<const N: usize>(values: &[&str]) -> Option<[f32; N]> {
+    let values: Vec<f32> = values.iter().map(|value| value.parse().ok()).collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+fn parse_index_row(values: &[&str]) -> Option<(u32, u64, f32, ContainerPose)> {
+    let [frame, step, time, pose @ ..] = values else {
+        return None;
+    };
+    let [x, y, angle, half_width, half_height] = parse_floats(pose)?;
+    let pose = ContainerPose {
+        centre: Vec2::new(x, y),
+        angle,
+        half_size: Vec2::new(half_width, half_height),
+    };
+    Some((frame.parse().ok()?, step.parse().ok()?, time.parse().ok()?, pose))
+}
+
+fn parse_particle_row(values: &[&str]) -> Option<RecordedParticle> {
+    let (id, values) = values.split_first()?;
+    let [x, y, vx, vy, ax, ay, density, pressure] = parse_floats(values)?;
+    Some(RecordedParticle {
+        id: id.parse().ok()?,
+        position: Vec2::new(x, y),
+        velocity: Vec2::new(vx, vy),
+        acceleration: Vec2::new(ax, ay),
+        density,
+        pressure,
+    })
+}
+
+impl Replay {
+    pub fn load(dir: &Path) -> Result<Self, ReplayError> {
+        let config_path = dir.join(recorder::CONFIG_FILE);
+        let text = std::fs::read_to_string(&config_path)
+            .map_err(|err| ReplayError::Io(config_path.clone(), err))?;
+        let config: SimConfig = ron::from_str(&text)
+            .map_err(|err| ReplayError::ParseConfig(config_path, err))?;
+        config.validate().map_err(ReplayError::Config)?;
+        let index_path = dir.join(recorder::INDEX_FILE);
+        let index = read_rows(&index_path, parse_index_row)?;
+        if let Some(pair) = index.windows(2).find(|pair| pair[1].2 <= pair[0].2) {
+            return Err(ReplayError::Unordered { path: index_path, frame: pair[1].0 });
+        }
+        let frames: Vec<Frame> = index
+            .into_iter()
+            .map(|(frame, step, time, container)| {
+                let path = Recorder::frame_path(dir, frame, "csv");
+                let mut particles = read_rows(&path, parse_particle_row)?;
+                particles.sort_by_key(|particle| particle.id);
+                Ok(Frame { step, time, container, particles })
+            })
+            .collect::<Result<_, ReplayError>>()?;
+        if frames.is_empty() {
+            return Err(ReplayError::Empty(dir.to_path_buf()));
+        }
+        Ok(Self { config, frames: frames.into() })
+    }
+
+    pub fn start_time(&self) -> f32 {
+        self.frames[0].time
+    }
+
+    pub fn end_time(&self) -> f32 {
+        self.frames[self.frames.len() - 1].time
+    }
+
+    /// The index of the latest frame at or before `time`,
+    /// and how far `time` is towards the frame after it.
+    pub fn frame_at(&self, time: f32) -> (usize, f32) {
+        let next = self.frames.partition_point(|frame| frame.time <= time);
+        if next == 0 {
+            return (0, 0.0);
+        }
+        if next == self.frames.len() {
+            return (next - 1, 0.0);
+        }
+        let (before, after) = (&self.frames[next - 1], &self.frames[next]);
+        (next - 1, (time - before.time) / (after.time - before.time))
+    }
+}
+
+/// Where and how fast the replay is playing.
+#[derive(Resource)]
+pub struct Playback {
+    // Playhead position in simulated time.
+    pub time: f32,
+    // Simulated seconds per real second.
+    pub speed: f32,
+    pub playing: bool,
+    pub reversed: bool,
+    // The particle shown for each recorded particle, by id.
+    particles: HashMap<u64, Entity>,
+}
+
+/// Plays back a recorded run in place of the simulation,
+/// feeding the recorded frames to the usual particle and density rendering.
+pub struct ReplayPlugin {
+    pub replay: Replay,
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Kernels::new(&self.replay.config))
+            .insert_resource(self.replay.config.clone())
+            .insert_resource(self.replay.clone())
+            .insert_resource(Container::new(self.replay.frames[0].container))
+            .insert_resource(Playback {
+                time: self.replay.start_time(),
+                speed: 1.0,
+                playing: true,
+                reversed: false,
+                particles: HashMap::default(),
+            })
+            // Spawn particles before they are drawn.
+            .add_systems(PreUpdate, (controls, advance, show_frame).chain());
+    }
+}
+
+pub fn controls(
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    replay: Res<Replay>,
+    mut playback: ResMut<Playback>,
+) {
+    let Some(keys) = keys else {
+        return;
+    };
+    if keys.just_pressed(KeyCode::Space) {
+        // Restart from the beginning if playback ran off the end.
+        let (start, end) = (replay.start_time(), replay.end_time());
+        if playback.reversed && playback.time <= start {
+            playback.time = end;
+        } else if !playback.reversed && playback.time >= end {
+            playback.time = start;
+        }
+        playback.playing = !playback.playing;
+    }
+    if keys.just_pressed(KeyCode::KeyR) {
+        playback.reversed = !playback.reversed;
+    }
+    if keys.just_pressed(KeyCode::ArrowUp) {
+        playback.speed = (playback.speed * 2.0).min(MAX_SPEED);
+    }
+    if keys.just_pressed(KeyCode::ArrowDown) {
+        playback.speed = (playback.speed * 0.5).max(MIN_SPEED);
+    }
+    // Scrub one frame at a time.
+    let (frame, fraction) = replay.frame_at(playback.time);
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        let next = (frame + 1).min(replay.frames.len() - 1);
+        playback.time = replay.frames[next].time;
+        playback.playing = false;
+    }
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        let previous = if fraction > 0.0 { frame } else { frame.saturating_sub(1) };
+        playback.time = replay.frames[previous].time;
+        playback.playing = false;
+    }
+    if keys.just_pressed(KeyCode::Home) {
+        playback.time = replay.start_time();
+    }
+    if keys.just_pressed(KeyCode::End) {
+        playback.time = replay.end_time();
+    }
+}
+
+pub fn advance(
+    time: Res<Time>,
+    replay: Res<Replay>,
+    mut playback: ResMut<Playback>,
+) {
+    if !playback.playing {
+        return;
+    }
+    let direction = if playback.reversed { -1.0 } else { 1.0 };
+    let (start, end) = (replay.start_time(), replay.end_time());
+    playback.time += direction * playback.speed * time.delta_secs();
+    // Stop on reaching the end being played towards.
+    let finished = if playback.reversed { playback.time <= start } else { playback.time >= end };
+    if finished {
+        playback.time = playback.time.clamp(start, end);
+        playback.playing = false;
+    }
+}
+
+/// Moves the particles and container to the playhead, interpolating between frames.
+pub fn show_frame(
+    mut commands: Commands,
+    mut particles: Query<(
+        &mut ParticlePosition,
+        &mut StepStartPosition,
+        &mut ParticleVelocity,
+        &mut ParticleAcceleration,
+        &mut ParticleDensity,
+        &mut ParticlePressure,
+    )>,
+    replay: Res<Replay>,
+    mut playback: ResMut<Playback>,
+    mut container: ResMut<Container>,
+) {
+    let (frame, fraction) = replay.frame_at(playback.time);
+    let current = &replay.frames[frame];
+    let next = replay.frames.get(frame + 1);
+    let pose = match next {
+        Some(next) => {
+            let (a, b) = (current.container, next.container);
+            ContainerPose {
+                centre: a.centre.lerp(b.centre, fraction),
+                angle: a.angle + (b.angle - a.angle) * fraction,
+                half_size: a.half_size.lerp(b.half_size, fraction),
+            }
+        }
+        None => current.container,
+    };
+    *container = Container::new(pose);
+
+    let half_size = pose.half_size;
+    let periodic = replay.config.periodic;
+    // Particles which are gone by the next frame stay where they were last recorded.
+    let recorded = |a: &RecordedParticle| match next.and_then(|next| next.particle(a.id)) {
+        Some(b) => {
+            // Particles which wrapped around a periodic side jump rather than sweep across.
+            let jump = (b.position - a.position).abs();
+            let wrapped = (periodic.0 && jump.x > half_size.x) || (periodic.1 && jump.y > half_size.y);
+            RecordedParticle {
+                id: a.id,
+                position: if wrapped { a.position } else { a.position.lerp(b.position, fraction) },
+                velocity: a.velocity.lerp(b.velocity, fraction),
+                acceleration: a.acceleration.lerp(b.acceleration, fraction),
+                density: a.density + (b.density - a.density) * fraction,
+                pressure: a.pressure + (b.pressure - a.pressure) * fraction,
+            }
+        }
+        None => *a,
+    };
+
+    // Despawn particles missing from the frame, such as drained ones.
+    playback.particles.retain(|id, entity| {
+        let kept = current.particle(*id).is_some();
+        if !kept {
+            commands.entity(*entity).despawn();
+        }
+        kept
+    });
+
+    for particle in &current.particles {
+        let state = recorded(particle);
+        let Some(entity) = playback.particles.get(&particle.id) else {
+            let entity = commands.spawn((
+                ParticlePosition(state.position),
+                StepStartPosition(state.position),
+                ParticleVelocity(state.velocity),
+                ParticleAcceleration(state.acceleration),
+                ParticleDensity(state.density),
+                ParticlePressure(state.pressure),
+            )).id();
+            playback.particles.insert(particle.id, entity);
+            continue;
+        };
+        let Ok((
+            mut position,
+            mut step_start,
+            mut velocity,
+            mut acceleration,
+            mut density,
+            mut pressure,
+        )) = particles.get_mut(*entity) else {
+            continue;
+        };
+        position.0 = state.position;
+        // Rendering interpolates from the step start, so keep the two equal.
+        step_start.0 = state.position;
+        velocity.0 = state.velocity;
+        acceleration.0 = state.acceleration;
+        density.0 = state.density;
+        pressure.0 = state.pressure;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_going_back_in_time_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("flow_unordered_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ron::to_string(&SimConfig::default()).unwrap();
+        std::fs::write(dir.join(recorder::CONFIG_FILE), config).unwrap();
+        // As if rewound between the second and third frames.
+        let index = format!(
+            "{}\n0,1,0.1,0,0,0,6,3.5\n1,2,0.2,0,0,0,6,3.5\n2,1,0.1,0,0,0,6,3.5\n",
+            recorder::INDEX_HEADER,
+        );
+        std::fs::write(dir.join(recorder::INDEX_FILE), index).unwrap();
+        let result = Replay::load(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(ReplayError::Unordered { frame: 2, .. })));
+    }
+}
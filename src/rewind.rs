@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+
+use bevy::{ecs::entity::{EntityHashMap, EntityHashSet}, prelude::*};
+
+use crate::config::SimConfig;
+use crate::container::{Container, ContainerPose};
+use crate::emitter::Emitter;
+use crate::particle::{
+    ParticleAcceleration,
+    ParticleDensity,
+    ParticlePosition,
+    ParticlePressure,
+    ParticleVelocity,
+    PrevParticlePosition,
+    StepStartPosition,
+};
+use crate::physics::StartupDamping;
+use crate::random::SimRng;
+use crate::simulation::{self, SimClock, SimPaused, SimulationSet};
+use crate::snapshot::ParticleState;
+
+/// The simulation state at the end of one fixed step.
+struct RewindFrame {
+    elapsed: f32,
+    step: u64,
+    dt: f32,
+    startup_damping: f32,
+    container: ContainerPose,
+    // Resuming draws the same random numbers and emits at the same times.
+    rng: SimRng,
+    emitters: Vec<f32>,
+    particles: Vec<(Entity, ParticleState)>,
+}
+
+/// The last few seconds of simulation state, to scrub back through.
+#[derive(Resource)]
+pub struct Rewind {
+    frames: VecDeque<RewindFrame>,
+    capacity: usize,
+    // The frame being shown while scrubbing, if the simulation is paused.
+    cursor: Option<usize>,
+}
+
+impl Rewind {
+    /// How far behind the latest buffered state the shown state is, in seconds.
+    pub fn offset(&self) -> Option<f32> {
+        let cursor = self.cursor?;
+        let latest = self.frames.back()?;
+        Some(latest.elapsed - self.frames[cursor].elapsed)
+    }
+}
+
+/// Keeps the last `seconds` of fixed steps. Holding Left scrubs back through
+/// them and Right forward, pausing the simulation; Enter resumes from the
+/// state shown, dropping the steps after it.
+pub struct RewindPlugin {
+    pub seconds: f32,
+}
+
+impl Plugin for RewindPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Rewind {
+                frames: VecDeque::new(),
+                capacity: 0,
+                cursor: None,
+            })
+            .add_systems(Startup, set_capacity(self.seconds))
+            .add_systems(Update, controls)
+            .add_systems(FixedUpdate, capture.after(SimulationSet).run_if(simulation::running));
+    }
+}
+
+fn set_capacity(seconds: f32) -> impl Fn(ResMut<Rewind>, Res<SimConfig>) {
+    move |mut rewind, config| {
+        rewind.capacity = (seconds / config.timestep).ceil().max(1.0) as usize;
+    }
+}
+
+type ParticleQuery<'a> = (
+    Entity,
+    &'a mut PrevParticlePosition,
+    &'a mut ParticlePosition,
+    &'a mut StepStartPosition,
+    &'a mut ParticleVelocity,
+    &'a mut ParticleAcceleration,
+    &'a mut ParticleDensity,
+    &'a mut ParticlePressure,
+);
+
+fn capture(
+    mut rewind: ResMut<Rewind>,
+    particles: Query<ParticleQuery>,
+    emitters: Query<&Emitter>,
+    clock: Res<SimClock>,
+    damping: Res<StartupDamping>,
+    container: Res<Container>,
+    rng: Res<SimRng>,
+) {
+    if rewind.frames.len() == rewind.capacity {
+        rewind.frames.pop_front();
+    }
+    let particles = particles
+        .iter()
+        .map(|(entity, prev_x, x, _, v, a, density, pressure)| (entity, ParticleState {
+            position: x.0,
+            prev_position: prev_x.0,
+            velocity: v.0,
+            acceleration: a.0,
+            density: density.0,
+            pressure: pressure.0,
+        }))
+        .collect();
+    rewind.frames.push_back(RewindFrame {
+        elapsed: clock.elapsed,
+        step: clock.step,
+        dt: clock.dt,
+        startup_damping: damping.0,
+        container: container.pose,
+        rng: rng.clone(),
+        emitters: emitters.iter().map(|emitter| emitter.pending).collect(),
+        particles,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn controls(
+    mut commands: Commands,
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    mut rewind: ResMut<Rewind>,
+    mut paused: ResMut<SimPaused>,
+    mut particles: Query<ParticleQuery>,
+    mut emitters: Query<&mut Emitter>,
+    mut clock: ResMut<SimClock>,
+    mut damping: ResMut<StartupDamping>,
+    mut container: ResMut<Container>,
+    mut rng: ResMut<SimRng>,
+) {
+    let Some(keys) = keys else {
+        return;
+    };
+    if rewind.frames.is_empty() {
+        return;
+    }
+    let latest = rewind.frames.len() - 1;
+
+    if let Some(cursor) = rewind.cursor {
+        if keys.just_pressed(KeyCode::Enter) {
+            // Resume from the shown state, forgetting the steps after it.
+            rewind.frames.truncate(cursor + 1);
+            rewind.cursor = None;
+            paused.0 = false;
+            return;
+        }
+    }
+
+    // Move one fixed step per frame while a key is held.
+    let cursor = rewind.cursor.unwrap_or(latest);
+    let target = if keys.pressed(KeyCode::ArrowLeft) {
+        cursor.saturating_sub(1)
+    } else if keys.pressed(KeyCode::ArrowRight) && rewind.cursor.is_some() {
+        (cursor + 1).min(latest)
+    } else {
+        return;
+    };
+    rewind.cursor = Some(target);
+    paused.0 = true;
+
+    let frame = &rewind.frames[target];
+    clock.elapsed = frame.elapsed;
+    clock.step = frame.step;
+    clock.dt = frame.dt;
+    damping.0 = frame.startup_damping;
+    *container = Container::new(frame.container);
+    *rng = frame.rng.clone();
+    for (mut emitter, pending) in emitters.iter_mut().zip(&frame.emitters) {
+        emitter.pending = *pending;
+    }
+    if frame.particles.iter().any(|(entity, _)| !particles.contains(*entity)) {
+        // Particles were removed since this frame. Respawn them all in the
+        // frame's order, which the neighbour sums depend on, so resuming
+        // from here repeats the run.
+        for (entity, ..) in &particles {
+            commands.entity(entity).despawn();
+        }
+        let respawned: EntityHashMap<Entity> = frame.particles
+            .iter()
+            .map(|(entity, state)| (*entity, commands.spawn(state.bundle()).id()))
+            .collect();
+        // Every buffered frame must find the respawned particles again.
+        for frame in &mut rewind.frames {
+            for (entity, _) in &mut frame.particles {
+                if let Some(new) = respawned.get(entity) {
+                    *entity = *new;
+                }
+            }
+        }
+        return;
+    }
+    // Particles spawned since this frame don't exist in it.
+    let kept: EntityHashSet = frame.particles.iter().map(|(entity, _)| *entity).collect();
+    for (entity, ..) in &particles {
+        if !kept.contains(&entity) {
+            commands.entity(entity).despawn();
+        }
+    }
+    for (entity, state) in &frame.particles {
+        let Ok((
+            _,
+            mut prev_x,
+            mut x,
+            mut step_start,
+            mut v,
+            mut a,
+            mut density,
+            mut pressure,
+        )) = particles.get_mut(*entity) else {
+            continue;
+        };
+        prev_x.0 = state.prev_position;
+        x.0 = state.position;
+        // Show the state as is, rather than interpolated from the last step.
+        step_start.0 = state.position;
+        v.0 = state.velocity;
+        a.0 = state.acceleration;
+        density.0 = state.density;
+        pressure.0 = state.pressure;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emitter::DrainConfig;
+    use crate::test_utils::{app, particles, run_until, test_config, with_emitter};
+    use crate::simulation::Solver;
+
+    fn draining_config() -> SimConfig {
+        SimConfig {
+            drains: vec![DrainConfig { centre: (0.0, -3.0), size: (2.0, 1.0) }],
+            ..with_emitter(test_config(Solver::Sph))
+        }
+    }
+
+    fn rewinding_app() -> App {
+        let mut app = app(draining_config());
+        app.add_plugins(RewindPlugin { seconds: 10.0 });
+        app
+    }
+
+    // Keys are read after the fixed update, so the first frame holding
+    // Left while running still takes a step.
+    fn hold(app: &mut App, key: KeyCode, frames: usize) {
+        let mut keys = ButtonInput::<KeyCode>::default();
+        keys.press(key);
+        app.insert_resource(keys);
+        for _ in 0..frames {
+            app.update();
+        }
+        app.world_mut().remove_resource::<ButtonInput<KeyCode>>();
+    }
+
+    #[test]
+    fn rewound_run_continues_the_same_run() {
+        let mut original = rewinding_app();
+        run_until(&mut original, 90);
+
+        let mut rewound = rewinding_app();
+        run_until(&mut rewound, 150);
+        let count = particles(&mut rewound).len();
+        // Scrub past the original step, then forward again, so respawned
+        // particles have to be found in frames other than the one they came back in.
+        hold(&mut rewound, KeyCode::ArrowLeft, 81);
+        hold(&mut rewound, KeyCode::ArrowRight, 20);
+        assert_eq!(rewound.world().resource::<SimClock>().step, 90);
+        hold(&mut rewound, KeyCode::Enter, 1);
+        run_until(&mut rewound, 150);
+        assert_eq!(particles(&mut rewound).len(), count);
+
+        hold(&mut rewound, KeyCode::ArrowLeft, 61);
+        hold(&mut rewound, KeyCode::Enter, 1);
+        assert_eq!(rewound.world().resource::<SimClock>().step, 90);
+        run_until(&mut original, 150);
+        run_until(&mut rewound, 150);
+
+        assert_eq!(particles(&mut original), particles(&mut rewound));
+    }
+}
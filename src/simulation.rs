@@ -0,0 +1,264 @@
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimConfig;
+use crate::container::{self, Container, ContainerInput};
+use crate::emitter;
+use crate::interaction::InteractionForce;
+use crate::kernel::Kernels;
+use crate::particle::{self, ParticleAcceleration, ParticleVelocity};
+use crate::pbf::{self, PbfIteration};
+use crate::pcisph::{self, PcisphCorrection, PcisphPrediction, PcisphStats};
+use crate::physics::{self, StartupDamping, TimestepLimit};
+use crate::random::SimRng;
+use crate::snapshot::{self, LoadSnapshot, SaveSnapshot};
+use crate::spatial_hash::{self, PositionHash, SpatialHash};
+
+/// Runs every fixed update to advance the physics by one fixed timestep.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationSet;
+
+/// The physics chain, run once per substep.
+/// Only touches physical particle components.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationStep;
+
+/// How each substep moves the particles, as named in config files.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Solver {
+    // Smoothed particle hydrodynamics, with an explicit pressure force.
+    Sph,
+    // Macklin and Müller's Position Based Fluids, which predicts positions
+    // from the external forces alone, then moves particles until their
+    // density constraints hold. Stays stiff without exploding.
+    Pbf,
+    // Solenthaler and Pajarola's predictive-corrective SPH, which raises
+    // pressures until the predicted density error is small enough.
+    Pcisph,
+}
+
+/// Simulated time, which advances independently of the frame rate.
+#[derive(Resource, Default)]
+pub struct SimClock {
+    // The length of the current substep.
+    pub dt: f32,
+    // The length of the substep before, which Verlet integration needs
+    // to tell velocity from how far particles moved.
+    pub prev_dt: f32,
+    // Total simulated time before the current substep.
+    pub elapsed: f32,
+    // How many substeps were taken before the current one.
+    pub step: u64,
+    // What limited the length of the current substep.
+    pub limit: TimestepLimit,
+    // How many substeps the latest fixed step took.
+    pub substeps: u32,
+}
+
+#[derive(Resource)]
+pub struct AverageEK(pub f32);
+
+/// While set, fixed updates leave the simulation state untouched.
+#[derive(Resource, Default)]
+pub struct SimPaused(pub bool);
+
+/// Run condition for systems which should only run while the simulation does.
+pub fn running(paused: Res<SimPaused>) -> bool {
+    !paused.0
+}
+
+/// Spawns the particles and steps the physics on a fixed timestep.
+/// Needs nothing beyond `MinimalPlugins`, so it can run without a window.
+/// Panics if the config doesn't pass `SimConfig::validate`.
+pub struct SimulationPlugin {
+    pub config: SimConfig,
+}
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        // Configs loaded from files are already checked, but ones built in code aren't.
+        if let Err(err) = self.config.validate() {
+            panic!("invalid simulation config: {}", err);
+        }
+        // Without a fixed seed, pick one and report it so the run can be repeated.
+        let seed = self.config.seed.unwrap_or_else(rand::random);
+        info!("random seed: {}", seed);
+        let kernels = Kernels::new(&self.config);
+        app
+            .insert_resource(SimRng::new(seed))
+            .insert_resource(StartupDamping(if self.config.startup_damping {0.0} else {1.0}))
+            .insert_resource(AverageEK(0.0))
+            .insert_resource(Time::<Fixed>::from_duration(self.config.timestep_duration()))
+            .init_resource::<SimClock>()
+            .init_resource::<SimPaused>()
+            .init_resource::<InteractionForce>()
+            .insert_resource(Container::initial(&self.config, &kernels))
+            .init_resource::<ContainerInput>()
+            .init_resource::<SpatialHash>()
+            .init_resource::<PositionHash>()
+            .init_resource::<PcisphStats>()
+            .insert_resource(kernels)
+            .insert_resource(self.config.clone())
+            .add_event::<SaveSnapshot>()
+            .add_event::<LoadSnapshot>()
+            .add_systems(Startup, (particle::spawn, emitter::spawn))
+            // Restore before the fixed update, and save once the frame's steps are done.
+            .add_systems(PreUpdate, snapshot::load)
+            .add_systems(Last, snapshot::save)
+            .add_systems(SimulationStep, (
+                container::update,
+                (
+                    physics::update_startup_damping
+                        .run_if(|config: Res<SimConfig>| config.startup_damping),
+                    particle::predict_positions,
+                    spatial_hash::update,
+                    spatial_hash::update_positions,
+                    particle::update_densities_and_pressures,
+                    particle::update_surface_normals
+                        .run_if(|config: Res<SimConfig>| config.surface_tension > 0.0),
+                    particle::update_accelerations,
+                    particle::update_positions,
+                ).chain().run_if(|config: Res<SimConfig>| config.solver == Solver::Sph),
+                (
+                    pbf::predict_positions,
+                    pbf::solve,
+                    spatial_hash::update,
+                    pbf::update_velocities,
+                    particle::commit_predicted_positions,
+                ).chain().run_if(|config: Res<SimConfig>| config.solver == Solver::Pbf),
+                (
+                    pcisph::reset,
+                    spatial_hash::update,
+                    // Viscosity is weighted by the densities at the start of the substep.
+                    pcisph::update_densities,
+                    pcisph::update_non_pressure_accelerations,
+                    pcisph::solve,
+                    pcisph::update_velocities,
+                    particle::commit_predicted_positions,
+                ).chain().run_if(|config: Res<SimConfig>| config.solver == Solver::Pcisph),
+                particle::update_average_ek,
+            ).chain())
+            .add_systems(PbfIteration, (
+                spatial_hash::update,
+                pbf::update_lambdas,
+                pbf::update_corrections,
+                pbf::apply_corrections,
+            ).chain())
+            .add_systems(PcisphPrediction, (
+                pcisph::predict_positions,
+                spatial_hash::update,
+                pcisph::update_densities,
+            ).chain())
+            .add_systems(PcisphCorrection, (
+                pcisph::update_pressures,
+                pcisph::update_accelerations,
+            ).chain())
+            .configure_sets(FixedUpdate, SimulationSet.run_if(running))
+            .add_systems(FixedUpdate, (
+                particle::store_step_start,
+                run_substeps,
+                emitter::drain,
+                emitter::emit,
+            ).chain().in_set(SimulationSet));
+    }
+}
+
+/// Advances the physics by one fixed timestep, either as `substeps` equal
+/// substeps or, in adaptive mode, as the longest substeps which stay stable.
+fn run_substeps(
+    world: &mut World,
+    particles: &mut QueryState<(&ParticleVelocity, &ParticleAcceleration)>,
+) {
+    let config = world.resource::<SimConfig>();
+    let max_dt = config.timestep / config.substeps as f32;
+    if !config.adaptive_timestep {
+        let substeps = config.substeps;
+        for _ in 0..substeps {
+            run_substep(world, max_dt, TimestepLimit::Substep);
+        }
+        world.resource_mut::<SimClock>().substeps = substeps;
+        return;
+    }
+
+    let max_substeps = config.max_substeps;
+    let mut remaining = config.timestep;
+    let mut substeps = 0;
+    while remaining > 0.0 {
+        let (max_speed, max_acceleration) = particles.iter(world).fold(
+            (0.0_f32, 0.0_f32),
+            |(speed, acceleration), (ParticleVelocity(v), ParticleAcceleration(a))| {
+                (speed.max(v.length()), acceleration.max(a.length()))
+            },
+        );
+        let (mut dt, limit) = physics::stable_timestep(
+            max_speed,
+            max_acceleration,
+            max_dt,
+            world.resource::<SimConfig>(),
+            world.resource::<Kernels>(),
+        );
+        // Give up on stability rather than take more than `max_substeps`.
+        dt = dt.max(remaining / (max_substeps - substeps) as f32);
+        // Finish exactly at the end of the fixed step.
+        if dt >= remaining {
+            dt = remaining;
+        }
+        run_substep(world, dt, limit);
+        remaining -= dt;
+        substeps += 1;
+    }
+    world.resource_mut::<SimClock>().substeps = substeps;
+}
+
+fn run_substep(world: &mut World, dt: f32, limit: TimestepLimit) {
+    let mut clock = world.resource_mut::<SimClock>();
+    // Nothing has moved before the first substep.
+    clock.prev_dt = if clock.dt > 0.0 { clock.dt } else { dt };
+    clock.dt = dt;
+    clock.limit = limit;
+    world.run_schedule(SimulationStep);
+    let mut clock = world.resource_mut::<SimClock>();
+    clock.elapsed += dt;
+    clock.step += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{run, test_config, with_emitter};
+
+    #[test]
+    fn serial_matches_parallel() {
+        for solver in [Solver::Sph, Solver::Pbf, Solver::Pcisph] {
+            let serial = run(SimConfig { multithreaded: false, ..test_config(solver) }, 60);
+            let parallel = run(SimConfig { multithreaded: true, ..test_config(solver) }, 60);
+            assert_eq!(serial, parallel, "{:?}", solver);
+        }
+    }
+
+    #[test]
+    fn pcisph_does_not_fling_settled_particles() {
+        // Particles settling into the bottom corners can't be spread out by
+        // pressure, which without `MAX_PRESSURE_SHIFT` grows until it flings them.
+        let config = SimConfig { num_particles: 150, ..test_config(Solver::Pcisph) };
+        let max_speed = run(config, 600)
+            .into_iter()
+            .map(|(_, v)| v.length())
+            .fold(0.0, f32::max);
+        assert!(max_speed < 2.0, "max speed {}", max_speed);
+    }
+
+    #[test]
+    #[should_panic(expected = "smoothing_radius")]
+    fn invalid_configs_are_rejected() {
+        run(SimConfig { smoothing_radius: 0.0, ..test_config(Solver::Sph) }, 1);
+    }
+
+    #[test]
+    fn fixed_seed_is_reproducible() {
+        let config = with_emitter(test_config(Solver::Sph));
+        let first = run(config.clone(), 120);
+        assert_eq!(first, run(config.clone(), 120));
+        assert_ne!(first, run(SimConfig { seed: Some(4), ..config }, 120));
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimConfig;
+use crate::container::{Container, ContainerPose};
+use crate::emitter::Emitter;
+use crate::particle::{
+    ParticleAcceleration,
+    ParticleCurvature,
+    ParticleDensity,
+    ParticleLambda,
+    ParticleNearDensity,
+    ParticleNearPressure,
+    ParticleNonPressureAcceleration,
+    ParticlePosition,
+    ParticlePositionCorrection,
+    ParticlePressure,
+    ParticleSurfaceNormal,
+    ParticleVelocity,
+    PrevParticlePosition,
+    PredictedParticlePosition,
+    StepStartPosition,
+};
+use crate::physics::StartupDamping;
+use crate::random::SimRng;
+use crate::simulation::SimClock;
+
+/// Bumped whenever the snapshot format changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// Config fields which only affect presentation, performance or user input,
+// so may differ between a snapshot and the running simulation.
+const UNCHECKED_FIELDS: [&str; 9] = [
+    "window_size",
+    "pixel_size",
+    "box_line_width",
+    "num_particles",
+    "multithreaded",
+    "seed",
+    "interaction_radius",
+    "interaction_strength",
+    // Switchable at runtime.
+    "near_density",
+];
+
+/// The file the snapshot keys save to and load from.
+#[derive(Resource)]
+pub struct SnapshotFile(pub PathBuf);
+
+/// Request to write the full simulation state to a file.
+#[derive(Event)]
+pub struct SaveSnapshot(pub PathBuf);
+
+/// Request to replace the simulation state with one read from a file.
+#[derive(Event)]
+pub struct LoadSnapshot(pub PathBuf);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ParticleState {
+    pub position: Vec2,
+    pub prev_position: Option<Vec2>,
+    pub velocity: Vec2,
+    pub acceleration: Vec2,
+    pub density: f32,
+    pub pressure: f32,
+}
+
+impl ParticleState {
+    /// The components to spawn a particle in this state with.
+    pub fn bundle(&self) -> impl Bundle {
+        (
+            ParticleDensity(self.density),
+            ParticlePressure(self.pressure),
+            ParticleNearDensity(0.0),
+            ParticleNearPressure(0.0),
+            ParticleSurfaceNormal(Vec2::ZERO),
+            ParticleCurvature(0.0),
+            ParticleLambda(0.0),
+            ParticlePositionCorrection(Vec2::ZERO),
+            ParticleNonPressureAcceleration(Vec2::ZERO),
+            PrevParticlePosition(self.prev_position),
+            ParticlePosition(self.position),
+            StepStartPosition(self.position),
+            PredictedParticlePosition(self.position),
+            ParticleVelocity(self.velocity),
+            ParticleAcceleration(self.acceleration),
+        )
+    }
+}
+
+type ParticleQuery<'a> = (
+    &'a ParticlePosition,
+    &'a PrevParticlePosition,
+    &'a ParticleVelocity,
+    &'a ParticleAcceleration,
+    &'a ParticleDensity,
+    &'a ParticlePressure,
+);
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub config: SimConfig,
+    pub seed: u64,
+    pub elapsed: f32,
+    pub step: u64,
+    // The length of the last substep, which the next one's Verlet step needs.
+    pub dt: f32,
+    pub startup_damping: f32,
+    pub container: ContainerPose,
+    // The generator part way through its stream.
+    pub rng: SimRng,
+    // The particles each emitter owes, in the order they were spawned.
+    pub emitters: Vec<f32>,
+    pub particles: Vec<ParticleState>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, ron::error::SpannedError),
+    Serialize(ron::Error),
+    Version { found: u32 },
+    Incompatible { field: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(path, err) =>
+                write!(f, "could not access {}: {}", path.display(), err),
+            SnapshotError::Parse(path, err) =>
+                write!(f, "could not parse {}: {}", path.display(), err),
+            SnapshotError::Serialize(err) =>
+                write!(f, "could not serialize snapshot: {}", err),
+            SnapshotError::Version { found } => write!(
+                f, "snapshot version {} is not supported, expected {}", found, SNAPSHOT_VERSION,
+            ),
+            SnapshotError::Incompatible { field } =>
+                write!(f, "snapshot config differs from the running config in `{}`", field),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl Snapshot {
+    pub fn write(&self, path: &Path) -> Result<(), SnapshotError> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(SnapshotError::Serialize)?;
+        std::fs::write(path, text).map_err(|err| SnapshotError::Io(path.to_path_buf(), err))
+    }
+
+    pub fn read(path: &Path) -> Result<Self, SnapshotError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| SnapshotError::Io(path.to_path_buf(), err))?;
+        // Check the version before the rest, in case the format has changed.
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        let options = ron::Options::default();
+        let Version { version } = options
+            .from_str::<Version>(&text)
+            .map_err(|err| SnapshotError::Parse(path.to_path_buf(), err))?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version { found: version });
+        }
+        ron::from_str(&text).map_err(|err| SnapshotError::Parse(path.to_path_buf(), err))
+    }
+
+    /// Checks that this snapshot's physics can be continued under `config`.
+    pub fn check_compatible(&self, config: &SimConfig) -> Result<(), SnapshotError> {
+        let ours = config_fields(&self.config)?;
+        let theirs = config_fields(config)?;
+        // A field missing from either side counts as a difference.
+        let mismatch = ours.keys()
+            .chain(theirs.keys())
+            .filter(|field| !UNCHECKED_FIELDS.contains(&field.as_str()))
+            .find(|field| ours.get(*field) != theirs.get(*field));
+        match mismatch {
+            Some(field) => Err(SnapshotError::Incompatible { field: field.clone() }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Each of a config's fields by name, as it would be written to a snapshot.
+fn config_fields(config: &SimConfig) -> Result<BTreeMap<String, ron::Value>, SnapshotError> {
+    let text = ron::to_string(config).map_err(SnapshotError::Serialize)?;
+    let value = ron::from_str(&text).map_err(|err| SnapshotError::Serialize(err.code))?;
+    let ron::Value::Map(fields) = value else {
+        unreachable!("configs always serialize to structs");
+    };
+    Ok(fields
+        .into_iter()
+        .filter_map(|(field, value)| match field {
+            ron::Value::String(field) => Some((field, value)),
+            _ => None,
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn save(
+    mut events: EventReader<SaveSnapshot>,
+    particles: Query<ParticleQuery>,
+    emitters: Query<&Emitter>,
+    clock: Res<SimClock>,
+    damping: Res<StartupDamping>,
+    rng: Res<SimRng>,
+    container: Res<Container>,
+    config: Res<SimConfig>,
+) {
+    for SaveSnapshot(path) in events.read() {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            config: config.clone(),
+            seed: rng.seed(),
+            elapsed: clock.elapsed,
+            step: clock.step,
+            dt: clock.dt,
+            startup_damping: damping.0,
+            container: container.pose,
+            rng: rng.clone(),
+            emitters: emitters.iter().map(|emitter| emitter.pending).collect(),
+            particles: particles
+                .iter()
+                .map(|(
+                    ParticlePosition(position),
+                    PrevParticlePosition(prev_position),
+                    ParticleVelocity(velocity),
+                    ParticleAcceleration(acceleration),
+                    ParticleDensity(density),
+                    ParticlePressure(pressure),
+                )| ParticleState {
+                    position: *position,
+                    prev_position: *prev_position,
+                    velocity: *velocity,
+                    acceleration: *acceleration,
+                    density: *density,
+                    pressure: *pressure,
+                })
+                .collect(),
+        };
+        match snapshot.write(path) {
+            Ok(()) => info!("saved snapshot to {}", path.display()),
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn load(
+    mut commands: Commands,
+    mut events: EventReader<LoadSnapshot>,
+    particles: Query<Entity, With<ParticlePosition>>,
+    mut emitters: Query<&mut Emitter>,
+    mut clock: ResMut<SimClock>,
+    mut damping: ResMut<StartupDamping>,
+    mut rng: ResMut<SimRng>,
+    mut container: ResMut<Container>,
+    config: Res<SimConfig>,
+) {
+    for LoadSnapshot(path) in events.read() {
+        let snapshot = match Snapshot::read(path)
+            .and_then(|snapshot| snapshot.check_compatible(&config).map(|_| snapshot))
+        {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                error!("{}", err);
+                continue;
+            }
+        };
+
+        for entity in &particles {
+            commands.entity(entity).despawn();
+        }
+        for particle in &snapshot.particles {
+            commands.spawn(particle.bundle());
+        }
+        clock.elapsed = snapshot.elapsed;
+        clock.step = snapshot.step;
+        clock.dt = snapshot.dt;
+        damping.0 = snapshot.startup_damping;
+        *container = Container::new(snapshot.container);
+        *rng = snapshot.rng;
+        for (mut emitter, pending) in emitters.iter_mut().zip(snapshot.emitters) {
+            emitter.pending = pending;
+        }
+        info!("loaded snapshot from {}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Solver;
+    use crate::test_utils::{app, particles, run_until, test_config, with_emitter};
+
+    fn emitting_config() -> SimConfig {
+        with_emitter(test_config(Solver::Sph))
+    }
+
+    #[test]
+    fn loaded_snapshot_continues_the_same_run() {
+        let path = std::env::temp_dir().join(format!("flow_snapshot_{}.ron", std::process::id()));
+
+        let mut original = app(emitting_config());
+        run_until(&mut original, 30);
+        original.world_mut().send_event(SaveSnapshot(path.clone()));
+        run_until(&mut original, 31);
+        run_until(&mut original, 90);
+
+        // A different seed, so only the saved generator state can match the original.
+        let mut loaded = app(SimConfig { seed: Some(4), ..emitting_config() });
+        loaded.world_mut().send_event(LoadSnapshot(path.clone()));
+        loaded.update();
+        run_until(&mut loaded, 90);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(particles(&mut original), particles(&mut loaded));
+    }
+
+    fn snapshot(config: SimConfig) -> Snapshot {
+        let seed = config.seed.unwrap_or(0);
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            seed,
+            container: ContainerPose::at_rest(&config),
+            config,
+            elapsed: 0.0,
+            step: 0,
+            dt: 0.0,
+            startup_damping: 1.0,
+            rng: SimRng::new(seed),
+            emitters: Vec::new(),
+            particles: Vec::new(),
+        }
+    }
+
+    fn incompatible_field(snapshot: &Snapshot, config: &SimConfig) -> Option<String> {
+        match snapshot.check_compatible(config) {
+            Ok(()) => None,
+            Err(SnapshotError::Incompatible { field }) => Some(field),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn unchecked_fields_may_differ() {
+        let config = SimConfig { seed: Some(u64::MAX), ..Default::default() };
+        let other = SimConfig {
+            window_size: (800, 600),
+            num_particles: 10,
+            multithreaded: !config.multithreaded,
+            seed: Some(1),
+            near_density: !config.near_density,
+            ..Default::default()
+        };
+        assert_eq!(incompatible_field(&snapshot(config), &other), None);
+    }
+
+    #[test]
+    fn physics_fields_must_match() {
+        let config = SimConfig::default();
+        let heavier = SimConfig { gravity_force: 20.0, ..Default::default() };
+        assert_eq!(
+            incompatible_field(&snapshot(config.clone()), &heavier).as_deref(),
+            Some("gravity_force"),
+        );
+
+        // Fields set on only one side count too.
+        let moving = SimConfig {
+            container_motion: Some(crate::container::ContainerMotion {
+                keyframes: Vec::new(),
+                looped: false,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            incompatible_field(&snapshot(config.clone()), &moving).as_deref(),
+            Some("container_motion"),
+        );
+        assert_eq!(
+            incompatible_field(&snapshot(moving), &config).as_deref(),
+            Some("container_motion"),
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let path = std::env::temp_dir().join(format!("flow_version_{}.ron", std::process::id()));
+        let old = Snapshot { version: SNAPSHOT_VERSION + 1, ..snapshot(SimConfig::default()) };
+        old.write(&path).unwrap();
+        let result = Snapshot::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(SnapshotError::Version { found }) if found == SNAPSHOT_VERSION + 1));
+    }
+}
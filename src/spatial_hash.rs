@@ -0,0 +1,227 @@
+use bevy::prelude::*;
+
+use crate::container::ContainerPose;
+use crate::kernel::Kernels;
+use crate::particle::{ParticlePosition, PredictedParticlePosition};
+
+const NEIGHBOUR_OFFSETS: [IVec2; 9] = [
+    IVec2::new(-1, -1), IVec2::new(0, -1), IVec2::new(1, -1),
+    IVec2::new(-1, 0), IVec2::new(0, 0), IVec2::new(1, 0),
+    IVec2::new(-1, 1), IVec2::new(0, 1), IVec2::new(1, 1),
+];
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub entity: Entity,
+    pub position: Vec2,
+    cell: IVec2,
+}
+
+/// Buckets particles by the cell they occupy so that neighbour searches
+/// only need to visit nearby particles rather than every particle.
+/// Cells are one kernel support radius wide, so every particle which can
+/// influence a point lies in the 3x3 block of cells around that point.
+#[derive(Resource, Default)]
+pub struct SpatialHash {
+    cell_size: f32,
+    cell_size_inv: f32,
+    // Entries sorted by bucket, in insertion order within each bucket.
+    entries: Vec<Entry>,
+    // Bucket `k` spans `entries[starts[k]..starts[k + 1]]`.
+    starts: Vec<usize>,
+}
+
+impl SpatialHash {
+    /// Rebuilds the hash from scratch using a counting sort over buckets.
+    pub fn rebuild(&mut self, particles: impl Iterator<Item = (Entity, Vec2)>, cell_size: f32) {
+        self.cell_size = cell_size;
+        self.cell_size_inv = 1.0 / cell_size;
+        let unsorted: Vec<Entry> = particles
+            .map(|(entity, position)| Entry {
+                entity,
+                position,
+                cell: self.cell_of(position),
+            })
+            .collect();
+        let num_buckets = unsorted.len().max(1);
+
+        // Count the entries in each bucket.
+        self.starts.clear();
+        self.starts.resize(num_buckets + 1, 0);
+        for entry in &unsorted {
+            self.starts[bucket_of(entry.cell, num_buckets) + 1] += 1;
+        }
+        // Prefix sum the counts to find where each bucket starts.
+        for k in 0..num_buckets {
+            self.starts[k + 1] += self.starts[k];
+        }
+        // Scatter entries into their buckets.
+        let mut next = self.starts.clone();
+        self.entries.clear();
+        self.entries.resize(unsorted.len(), Entry {
+            entity: Entity::PLACEHOLDER,
+            position: Vec2::ZERO,
+            cell: IVec2::ZERO,
+        });
+        for entry in unsorted {
+            let bucket = bucket_of(entry.cell, num_buckets);
+            self.entries[next[bucket]] = entry;
+            next[bucket] += 1;
+        }
+    }
+
+    /// Iterates over every particle in the 3x3 block of cells around `point`.
+    /// This is a superset of the particles within one cell size.
+    pub fn neighbours(&self, point: Vec2) -> impl Iterator<Item = &Entry> {
+        let centre = self.cell_of(point);
+        NEIGHBOUR_OFFSETS.iter().flat_map(move |offset| {
+            let cell = centre + *offset;
+            // Distinct cells can share a bucket, so filter on the cell itself.
+            self.bucket(cell).iter().filter(move |entry| entry.cell == cell)
+        })
+    }
+
+    /// Like `neighbours`, but also visits particles across the `periodic` sides
+    /// of the container, each with its minimum image displacement to `point`.
+    pub fn periodic_neighbours<'a>(
+        &'a self,
+        point: Vec2,
+        container: &ContainerPose,
+        periodic: (bool, bool),
+    ) -> impl Iterator<Item = (Vec2, &'a Entry)> {
+        container
+            .image_offsets(point, periodic, self.cell_size)
+            .flat_map(move |offset| {
+                let image = point + offset;
+                self.neighbours(image).map(move |entry| (image - entry.position, entry))
+            })
+    }
+
+    fn bucket(&self, cell: IVec2) -> &[Entry] {
+        if self.entries.is_empty() {
+            return &[];
+        }
+        let bucket = bucket_of(cell, self.starts.len() - 1);
+        &self.entries[self.starts[bucket]..self.starts[bucket + 1]]
+    }
+
+    fn cell_of(&self, position: Vec2) -> IVec2 {
+        (position * self.cell_size_inv).floor().as_ivec2()
+    }
+}
+
+/// The same buckets over the current rather than predicted positions, for the
+/// SPH density pass, which sums neighbours where they are now.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct PositionHash(pub SpatialHash);
+
+fn bucket_of(cell: IVec2, num_buckets: usize) -> usize {
+    let hash = (cell.x as u32).wrapping_mul(15_823)
+        ^ (cell.y as u32).wrapping_mul(9_737_333);
+    hash as usize % num_buckets
+}
+
+pub fn update(
+    particles: Query<(Entity, &PredictedParticlePosition)>,
+    mut hash: ResMut<SpatialHash>,
+    kernels: Res<Kernels>,
+) {
+    hash.rebuild(
+        particles.iter().map(|(entity, PredictedParticlePosition(x))| (entity, *x)),
+        kernels.support_radius(),
+    );
+}
+
+pub fn update_positions(
+    particles: Query<(Entity, &ParticlePosition)>,
+    mut hash: ResMut<PositionHash>,
+    kernels: Res<Kernels>,
+) {
+    hash.rebuild(
+        particles.iter().map(|(entity, ParticlePosition(x))| (entity, *x)),
+        kernels.support_radius(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::kernel::{Kernel, Spiky2};
+    use crate::random;
+
+    const RADIUS: f32 = 1.2;
+    const HALF_SIZE: Vec2 = Vec2::new(6.0, 3.5);
+
+    fn scattered_points(count: u32) -> Vec<(Entity, Vec2)> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|i| {
+                let (x, y) = random::point_in_box(&mut rng, (HALF_SIZE.x, HALF_SIZE.y));
+                (Entity::from_raw(i), Vec2::new(x, y))
+            })
+            .collect()
+    }
+
+    fn hashed(points: &[(Entity, Vec2)]) -> SpatialHash {
+        let mut hash = SpatialHash::default();
+        hash.rebuild(points.iter().copied(), RADIUS);
+        hash
+    }
+
+    fn assert_close(hashed: f32, brute: f32) {
+        assert!((hashed - brute).abs() <= 1e-4 * brute.max(1.0), "{} != {}", hashed, brute);
+    }
+
+    #[test]
+    fn neighbour_sums_match_brute_force() {
+        let kernel = Spiky2::new(RADIUS);
+        let points = scattered_points(500);
+        let hash = hashed(&points);
+        for (entity, point) in &points {
+            let hashed: f32 = hash
+                .neighbours(*point)
+                .filter(|neighbour| neighbour.entity != *entity)
+                .map(|neighbour| kernel.value((*point - neighbour.position).length_squared()))
+                .sum();
+            let brute: f32 = points
+                .iter()
+                .filter(|(other, _)| other != entity)
+                .map(|(_, other)| kernel.value((*point - *other).length_squared()))
+                .sum();
+            assert_close(hashed, brute);
+        }
+    }
+
+    #[test]
+    fn periodic_neighbour_sums_match_brute_force() {
+        let kernel = Spiky2::new(RADIUS);
+        let points = scattered_points(500);
+        let hash = hashed(&points);
+        let pose = ContainerPose { centre: Vec2::ZERO, angle: 0.0, half_size: HALF_SIZE };
+        let periodic = (true, true);
+        // The minimum image displacement, wrapping each axis into the box.
+        let wrap = |x: f32, half_size: f32| (x + half_size).rem_euclid(2.0 * half_size) - half_size;
+        for (entity, point) in &points {
+            let hashed: f32 = hash
+                .periodic_neighbours(*point, &pose, periodic)
+                .filter(|(_, neighbour)| neighbour.entity != *entity)
+                .map(|(displacement, _)| kernel.value(displacement.length_squared()))
+                .sum();
+            let brute: f32 = points
+                .iter()
+                .filter(|(other, _)| other != entity)
+                .map(|(_, other)| {
+                    let displacement = *point - *other;
+                    let displacement = Vec2::new(
+                        wrap(displacement.x, HALF_SIZE.x),
+                        wrap(displacement.y, HALF_SIZE.y),
+                    );
+                    kernel.value(displacement.length_squared())
+                })
+                .sum();
+            assert_close(hashed, brute);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Fixtures shared by the tests which run the simulation.
+
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+
+use crate::config::SimConfig;
+use crate::emitter::EmitterConfig;
+use crate::particle::{ParticlePosition, ParticleVelocity};
+use crate::simulation::{SimClock, SimulationPlugin, Solver};
+
+/// A small, seeded run, quick enough to step through in a test.
+pub fn test_config(solver: Solver) -> SimConfig {
+    SimConfig {
+        num_particles: 200,
+        seed: Some(3),
+        solver,
+        ..Default::default()
+    }
+}
+
+/// Adds an emitter, which draws from the random stream during the run,
+/// not just at startup.
+pub fn with_emitter(config: SimConfig) -> SimConfig {
+    SimConfig {
+        max_particles: 400,
+        emitters: vec![EmitterConfig {
+            position: (-5.0, 2.0),
+            direction: (1.0, 0.0),
+            rate: 50.0,
+            speed: 4.0,
+            spread: 10.0,
+        }],
+        ..config
+    }
+}
+
+/// The physics alone, taking one fixed step per update.
+pub fn app(config: SimConfig) -> App {
+    let timestep = TimeUpdateStrategy::ManualDuration(config.timestep_duration());
+    let mut app = App::new();
+    app
+        .add_plugins(MinimalPlugins)
+        .add_plugins(SimulationPlugin { config })
+        .insert_resource(timestep);
+    app
+}
+
+pub fn run_until(app: &mut App, step: u64) {
+    while app.world().resource::<SimClock>().step < step {
+        app.update();
+    }
+    assert_eq!(app.world().resource::<SimClock>().step, step);
+}
+
+/// Every particle's position and velocity.
+pub fn particles(app: &mut App) -> Vec<(Vec2, Vec2)> {
+    let world = app.world_mut();
+    world
+        .query::<(&ParticlePosition, &ParticleVelocity)>()
+        .iter(world)
+        .map(|(ParticlePosition(x), ParticleVelocity(v))| (*x, *v))
+        .collect()
+}
+
+/// Runs the physics for `steps` fixed steps and returns the particles.
+pub fn run(config: SimConfig, steps: u64) -> Vec<(Vec2, Vec2)> {
+    let mut app = app(config);
+    run_until(&mut app, steps);
+    particles(&mut app)
+}
@@ -1,6 +1,12 @@
 use bevy::prelude::*;
 
-use crate::physics::StartupDamping;
+use flow::SimConfig;
+use flow::particle::ParticlePosition;
+use flow::pcisph::PcisphStats;
+use flow::physics::StartupDamping;
+use flow::replay::{Playback, Replay};
+use flow::rewind::Rewind;
+use flow::simulation::{AverageEK, SimClock, Solver};
 
 #[derive(Component)]
 pub struct UI;
@@ -8,18 +14,49 @@ pub struct UI;
 #[derive(Resource)]
 pub struct UILastUpdate(pub f32);
 
-#[derive(Resource)]
-pub struct AverageEK(pub f32);
-
 // Frame rate UI constants.
 pub const FRAME_RATE_FONT_SIZE: f32 = 33.0;
 pub const FRAME_RATE_TEXT_PADDING: Val = Val::Px(5.0);
 pub const FRAME_RATE_UPDATE_INTERVAL: f32 = 0.2;
 
+#[allow(clippy::too_many_arguments)]
 pub fn update(
     time: Res<Time>,
+    particles: Query<(), With<ParticlePosition>>,
     ek: Res<AverageEK>,
     damping: Res<StartupDamping>,
+    clock: Res<SimClock>,
+    pcisph: Res<PcisphStats>,
+    config: Res<SimConfig>,
+    rewind: Option<Res<Rewind>>,
+    mut last_update: ResMut<UILastUpdate>,
+    ui_root: Single<Entity, (With<UI>, With<Text>)>,
+    mut writer: TextUiWriter,
+) {
+    if time.elapsed_secs() - last_update.0 > FRAME_RATE_UPDATE_INTERVAL {
+        last_update.0 += FRAME_RATE_UPDATE_INTERVAL;
+        let frame_rate = 1.0 / time.delta_secs();
+        *writer.text(*ui_root, 1) = format!("{:>5.2}", frame_rate);
+        *writer.text(*ui_root, 3) = format!("{}", particles.iter().len());
+        *writer.text(*ui_root, 5) = format!("{:>4.2}", ek.0);
+        *writer.text(*ui_root, 7) = format!("{:>4.2}", damping.0);
+        *writer.text(*ui_root, 9) = match rewind.and_then(|rewind| rewind.offset()) {
+            Some(offset) => format!("rewound {:.2} s, Enter to resume", offset),
+            None => format!("{:>6.4} x{} ({})", clock.dt, clock.substeps, clock.limit),
+        };
+        if config.solver == Solver::Pcisph {
+            writer.text(*ui_root, 9).push_str(&format!(
+                ", PCISPH: {:>2} iterations, {:>5.2}% error",
+                pcisph.iterations, 100.0 * pcisph.density_error,
+            ));
+        }
+    }
+}
+
+pub fn update_replay(
+    time: Res<Time>,
+    replay: Res<Replay>,
+    playback: Res<Playback>,
     mut last_update: ResMut<UILastUpdate>,
     ui_root: Single<Entity, (With<UI>, With<Text>)>,
     mut writer: TextUiWriter,
@@ -27,8 +64,18 @@ pub fn update(
     if time.elapsed_secs() - last_update.0 > FRAME_RATE_UPDATE_INTERVAL {
         last_update.0 += FRAME_RATE_UPDATE_INTERVAL;
         let frame_rate = 1.0 / time.delta_secs();
+        let (frame, _) = replay.frame_at(playback.time);
+        let state = match (playback.playing, playback.reversed) {
+            (false, _) => "paused",
+            (true, false) => "playing",
+            (true, true) => "reversed",
+        };
         *writer.text(*ui_root, 1) = format!("{:>5.2}", frame_rate);
-        *writer.text(*ui_root, 3) = format!("{:>4.2}", ek.0);
-        *writer.text(*ui_root, 5) = format!("{:>4.2}", damping.0);
+        *writer.text(*ui_root, 3) = format!("{}", replay.frames[frame].particles.len());
+        *writer.text(*ui_root, 5) = format!(
+            "{:>6.3} / {:.3}", playback.time, replay.end_time(),
+        );
+        *writer.text(*ui_root, 7) = format!("{} / {}", frame, replay.frames.len() - 1);
+        *writer.text(*ui_root, 9) = format!("x{} ({})", playback.speed, state);
     }
 }